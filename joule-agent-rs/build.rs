@@ -0,0 +1,13 @@
+// compiles proto/cryoflux.proto for the "grpc" feature; protox is a pure-Rust protobuf compiler,
+// so building doesn't need protoc installed
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/cryoflux.proto");
+        let fds = protox::compile(["proto/cryoflux.proto"], ["proto"]).expect("compile proto/cryoflux.proto");
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(fds)
+            .expect("generate gRPC code");
+    }
+}
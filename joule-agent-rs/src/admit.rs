@@ -0,0 +1,57 @@
+// Admission policy for POST /v1/admit: run, defer or reject a job given its energy estimate
+use serde::Serialize;
+
+#[derive(Clone, Copy, PartialEq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision { Run, Defer, Reject }
+
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct Admission {
+    pub decision: Decision,
+    pub reason: String,
+    pub balance_j: f64,
+    pub projected_j: f64, // balance after the job, if it ran now
+    pub retry_after_s: Option<f64>,
+}
+
+pub struct Policy {
+    pub priority_threshold: u8,     // priorities below this are "low"
+    pub high_water_j: Option<f64>, // low-priority jobs wait until the balance is at least this
+    pub cap_j: Option<f64>,
+}
+
+// how the bucket gets topped up: `Add` credits `joules_per_s` on average, `Set` resets it to
+// `joules` at the next refill
+pub enum Refill { Add { joules_per_s: f64 }, Set { joules: f64, next_in_s: f64 } }
+
+impl Refill {
+    // seconds until the balance reaches `target`, None if the refill never gets there
+    fn eta_s(&self, balance: f64, target: f64) -> Option<f64> {
+        match *self {
+            Refill::Add { joules_per_s } if joules_per_s > 0.0 => Some((target - balance).max(0.0) / joules_per_s),
+            Refill::Set { joules, next_in_s } if joules >= target => Some(next_in_s),
+            _ => None,
+        }
+    }
+}
+
+pub fn decide(p: &Policy, refill: Option<&Refill>, balance: f64, est_j: f64, priority: u8) -> Admission {
+    let projected_j = balance - est_j;
+    let out = |decision, reason: String, retry_after_s| Admission { decision, reason, balance_j: balance, projected_j, retry_after_s };
+    if p.cap_j.is_some_and(|cap| est_j > cap) {
+        return out(Decision::Reject, format!("estimate exceeds the bucket cap of {} J", p.cap_j.unwrap_or_default()), None);
+    }
+    if projected_j < 0.0 {
+        return match refill.and_then(|r| r.eta_s(balance, est_j)) {
+            Some(eta) => out(Decision::Defer, format!("short {:.1} J; the refill covers it", -projected_j), Some(eta)),
+            None => out(Decision::Reject, format!("short {:.1} J and no refill will cover it", -projected_j), None),
+        };
+    }
+    if priority < p.priority_threshold {
+        if let Some(hw) = p.high_water_j.filter(|hw| balance < *hw) {
+            let eta = refill.and_then(|r| r.eta_s(balance, hw));
+            return out(Decision::Defer, format!("low priority and the balance is below the {} J high-water mark", hw), eta);
+        }
+    }
+    out(Decision::Run, "enough energy in the bucket".into(), None)
+}
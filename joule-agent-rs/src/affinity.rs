@@ -0,0 +1,82 @@
+// Sampler placement (JOULE_SAMPLER_CPU, JOULE_SAMPLER_PRIORITY): with either set, the sampler
+// leaves the shared tokio workers for a thread of its own, pinned to one core and/or at a raised
+// scheduling priority, so a loaded machine can't make it miss ticks. Linux and Windows only;
+// elsewhere, or without the privilege, it is logged and the sampler runs unpinned
+use serde::Serialize;
+use std::future::Future;
+use tokio::task::JoinHandle;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority { Normal, High }
+
+// what actually took effect, for /v1/diag
+#[derive(Clone, Copy, Serialize, utoipa::ToSchema)]
+pub struct Placement {
+    pub dedicated_thread: bool,
+    pub cpu: Option<usize>, // null: not pinned
+    pub priority: Priority,
+}
+
+impl Default for Placement {
+    fn default() -> Placement { Placement { dedicated_thread: false, cpu: None, priority: Priority::Normal } }
+}
+
+// runs `sampler` on the runtime when nothing is asked for, else on a blocking-pool thread it
+// keeps for good, driving its own current-thread runtime; `placed` gets the outcome either way
+pub fn spawn<F>(sampler: F, cpu: Option<usize>, priority: Priority, placed: impl FnOnce(Placement) + Send + 'static) -> JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    if cpu.is_none() && priority == Priority::Normal {
+        placed(Placement::default());
+        return tokio::spawn(sampler);
+    }
+    tokio::task::spawn_blocking(move || {
+        let placement = Placement {
+            dedicated_thread: true,
+            cpu: cpu.filter(|&c| pin(c)),
+            priority: if priority == Priority::High && raise() { Priority::High } else { Priority::Normal },
+        };
+        tracing::info!(cpu = ?placement.cpu, priority = ?placement.priority, "sampler on a dedicated thread");
+        placed(placement);
+        match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+            Ok(rt) => rt.block_on(sampler),
+            Err(e) => tracing::error!(error = %e, "cannot start the sampler runtime"),
+        }
+    })
+}
+
+fn pin(cpu: usize) -> bool {
+    let Some(core) = core_affinity::get_core_ids().unwrap_or_default().into_iter().find(|c| c.id == cpu) else {
+        tracing::warn!(cpu, "JOULE_SAMPLER_CPU names no available core, sampler not pinned");
+        return false;
+    };
+    let ok = core_affinity::set_for_current(core);
+    if !ok { tracing::warn!(cpu, "cannot pin the sampler thread on this platform"); }
+    ok
+}
+
+// nice -10 on Linux (needs CAP_SYS_NICE), THREAD_PRIORITY_HIGHEST on Windows
+#[cfg(target_os = "linux")]
+fn raise() -> bool {
+    // SAFETY: plain syscalls on the calling thread; a thread id is a valid PRIO_PROCESS target on Linux
+    let ok = unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, -10) } == 0;
+    if !ok { tracing::warn!(error = %std::io::Error::last_os_error(), "cannot raise the sampler priority (needs CAP_SYS_NICE)"); }
+    ok
+}
+
+#[cfg(windows)]
+fn raise() -> bool {
+    use windows_sys::Win32::System::Threading::{GetCurrentThread, SetThreadPriority, THREAD_PRIORITY_HIGHEST};
+    // SAFETY: the pseudo-handle of the calling thread is always valid
+    let ok = unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_HIGHEST) } != 0;
+    if !ok { tracing::warn!(error = %std::io::Error::last_os_error(), "cannot raise the sampler priority"); }
+    ok
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn raise() -> bool {
+    tracing::warn!("JOULE_SAMPLER_PRIORITY is not supported on this platform");
+    false
+}
@@ -0,0 +1,147 @@
+// Budget and power-spike alerts POSTed to a webhook (JOULE_WEBHOOK_URL)
+use crate::{shutdown_requested, Last, State};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+
+pub struct AlertCfg {
+    pub url: String,
+    pub low_j: Option<f64>,
+    pub high_j: Option<f64>,
+    pub hysteresis_j: Option<f64>, // re-arm margin past a threshold; default 10% of it
+    pub spike_w: Option<f64>,
+    pub spike_dwell_s: f64,
+    pub min_interval_s: f64, // debounce: at most one event of each type per interval
+    pub max_retries: u32,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind { Low, High, Spike }
+
+#[derive(Serialize)]
+pub struct Event {
+    #[serde(rename = "type")]
+    kind: Kind,
+    bucket_j: f64,
+    net_w: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spike_s: Option<f64>, // how long net_w has been above the spike threshold
+    ts: f64,
+    host: String,
+    machine_id: String,
+}
+
+// edge-triggered: each alert fires when its condition starts; `armed` flips back on only after
+// the value has moved clear of the threshold again
+struct Watch {
+    low_armed: bool,
+    high_armed: bool,
+    spike_since: Option<f64>,
+    spike_fired: bool,
+    last_fired: [Option<f64>; 3],
+}
+
+impl Watch {
+    fn new() -> Watch {
+        Watch { low_armed: true, high_armed: true, spike_since: None, spike_fired: false, last_fired: [None; 3] }
+    }
+
+    fn observe(&mut self, cfg: &AlertCfg, s: &Last) -> Vec<Event> {
+        let mut fired = Vec::new();
+        if let Some(low) = cfg.low_j {
+            if self.low_armed && s.bucket_j < low {
+                self.low_armed = false;
+                fired.push((Kind::Low, None));
+            } else if s.bucket_j >= low + cfg.hysteresis_j.unwrap_or(0.1 * low.abs()) {
+                self.low_armed = true;
+            }
+        }
+        if let Some(high) = cfg.high_j {
+            if self.high_armed && s.bucket_j > high {
+                self.high_armed = false;
+                fired.push((Kind::High, None));
+            } else if s.bucket_j <= high - cfg.hysteresis_j.unwrap_or(0.1 * high.abs()) {
+                self.high_armed = true;
+            }
+        }
+        if let Some(spike) = cfg.spike_w {
+            if s.compute_net_w > spike {
+                let since = *self.spike_since.get_or_insert(s.ts);
+                if !self.spike_fired && s.ts - since >= cfg.spike_dwell_s {
+                    self.spike_fired = true;
+                    fired.push((Kind::Spike, Some(s.ts - since)));
+                }
+            } else {
+                self.spike_since = None;
+                self.spike_fired = false;
+            }
+        }
+        fired.into_iter()
+            .filter(|(kind, _)| {
+                let slot = &mut self.last_fired[*kind as usize];
+                let due = slot.is_none_or(|t| s.ts - t >= cfg.min_interval_s);
+                if due { *slot = Some(s.ts); }
+                due
+            })
+            .map(|(kind, spike_s)| Event {
+                kind, bucket_j: s.bucket_j, net_w: s.compute_net_w, spike_s, ts: s.ts,
+                host: s.host.to_string(), machine_id: s.machine_id.to_string(),
+            })
+            .collect()
+    }
+}
+
+pub async fn run(st: State, cfg: AlertCfg) {
+    // deliveries run behind a queue so a slow webhook never delays evaluation
+    let (tx, mut queue) = mpsc::channel::<Event>(32);
+    let client = reqwest::Client::new();
+    let (url, max_retries) = (cfg.url.clone(), cfg.max_retries);
+    let sender = tokio::spawn(async move {
+        while let Some(ev) = queue.recv().await { deliver(&client, &url, max_retries, &ev).await; }
+    });
+
+    let mut watch = Watch::new();
+    let mut rx = st.samples_tx.subscribe();
+    loop {
+        tokio::select! {
+            r = rx.recv() => match r {
+                Ok(s) if !s.warmup => {
+                    for ev in watch.observe(&cfg, &s) {
+                        if tx.try_send(ev).is_err() { tracing::warn!("alert queue full, event dropped"); }
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(n)) => tracing::warn!(missed = n, "alert watcher fell behind"),
+                Err(RecvError::Closed) => break,
+            },
+            _ = shutdown_requested(st.shutdown.clone()) => break,
+        }
+    }
+    // let queued events go out, but don't hold up shutdown on a dead webhook
+    drop(tx);
+    let _ = tokio::time::timeout(Duration::from_secs(5), sender).await;
+}
+
+// retry network errors, 429 and 5xx with exponential backoff, like the influx push
+async fn deliver(client: &reqwest::Client, url: &str, max_retries: u32, ev: &Event) {
+    let mut backoff = Duration::from_millis(500);
+    for attempt in 0..=max_retries {
+        let transient = match client.post(url).json(ev).timeout(Duration::from_secs(10)).send().await {
+            Ok(r) if r.status().is_success() => return,
+            Ok(r) => {
+                let status = r.status();
+                tracing::warn!(%status, attempt, "webhook rejected alert");
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, attempt, "webhook delivery failed");
+                true
+            }
+        };
+        if !transient || attempt == max_retries { break; }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(30));
+    }
+    tracing::warn!("dropping alert");
+}
@@ -0,0 +1,49 @@
+// Live grid carbon intensity (JOULE_CO2_API_URL), falling back to JOULE_CO2_G_PER_KWH
+use crate::{shutdown_requested, State};
+use std::time::Duration;
+
+pub struct CarbonCfg {
+    pub url: String,
+    pub pointer: String, // JSON pointer to the g/kWh value, e.g. /carbonIntensity (Electricity Maps)
+    pub token: Option<String>,
+    pub refresh_s: f64,
+}
+
+pub async fn run(st: State, cfg: CarbonCfg) {
+    let client = reqwest::Client::new();
+    let mut tick = tokio::time::interval(Duration::from_secs_f64(cfg.refresh_s.max(60.0)));
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {}
+            _ = shutdown_requested(st.shutdown.clone()) => break,
+        }
+        let intensity = match fetch(&client, &cfg).await {
+            Ok(v) => {
+                tracing::debug!(co2_g_per_kwh = v, "carbon intensity refreshed");
+                v
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, fallback = st.cfg.co2_g_per_kwh, "carbon intensity unavailable, using static value");
+                st.cfg.co2_g_per_kwh
+            }
+        };
+        *st.co2_g_per_kwh.lock() = intensity;
+    }
+}
+
+async fn fetch(client: &reqwest::Client, cfg: &CarbonCfg) -> Result<f64, String> {
+    let mut req = client.get(&cfg.url).timeout(Duration::from_secs(10));
+    if let Some(token) = &cfg.token { req = req.header("auth-token", token); }
+    let body: serde_json::Value = req
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    body.pointer(&cfg.pointer)
+        .and_then(|v| v.as_f64())
+        .filter(|v| v.is_finite() && *v >= 0.0)
+        .ok_or_else(|| format!("no non-negative number at {}", cfg.pointer))
+}
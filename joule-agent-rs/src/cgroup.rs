@@ -0,0 +1,48 @@
+// CPU usage inside a cgroup v2 with a CPU limit (containers): sysinfo sees the whole host, so
+// usage comes from cpu.stat usage_usec deltas against the cpu.max quota instead
+use std::{path::PathBuf, time::Instant};
+
+pub struct Cgroup {
+    stat: PathBuf,
+    quota_cpus: f64, // cpu.max quota / period, e.g. 1.5
+    prev: Option<(Instant, u64)>,
+}
+
+impl Cgroup {
+    // None outside a cgroup v2 hierarchy or without a CPU quota ("max"): the host view is right then
+    pub fn probe() -> Option<Cgroup> {
+        let root = PathBuf::from("/sys/fs/cgroup");
+        if !root.join("cgroup.controllers").exists() { return None; }
+        // v2 has a single "0::/path" line; inside a cgroup namespace the path is "/"
+        let own = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+        let rel = own.lines().find_map(|l| l.strip_prefix("0::"))?.trim_start_matches('/');
+        let dir = root.join(rel);
+        let max = std::fs::read_to_string(dir.join("cpu.max")).ok()?;
+        let mut parts = max.split_whitespace();
+        let quota: f64 = parts.next()?.parse().ok()?; // "max" fails to parse: no limit
+        let period: f64 = parts.next().and_then(|p| p.parse().ok()).unwrap_or(100_000.0);
+        if !(quota > 0.0 && period > 0.0) { return None; }
+        let stat = dir.join("cpu.stat");
+        read_usage_usec(&stat)?;
+        Some(Cgroup { stat, quota_cpus: quota / period, prev: None })
+    }
+
+    pub fn quota_cpus(&self) -> f64 { self.quota_cpus }
+
+    // 0..100 of the quota since the previous call; None on the first call or a failed read
+    pub fn usage_pct(&mut self) -> Option<f32> {
+        let now = Instant::now();
+        let usec = read_usage_usec(&self.stat)?;
+        let prev = self.prev.replace((now, usec));
+        let (t, u) = prev?;
+        let elapsed = (now - t).as_secs_f64();
+        if elapsed <= 0.0 { return None; }
+        let used_cpus = usec.saturating_sub(u) as f64 / 1e6 / elapsed;
+        Some((used_cpus / self.quota_cpus * 100.0).clamp(0.0, 100.0) as f32)
+    }
+}
+
+fn read_usage_usec(path: &std::path::Path) -> Option<u64> {
+    let raw = std::fs::read_to_string(path).ok()?;
+    raw.lines().find_map(|l| l.strip_prefix("usage_usec "))?.trim().parse().ok()
+}
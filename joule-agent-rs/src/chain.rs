@@ -0,0 +1,40 @@
+// Sample hash chain (JOULE_HASH_ALGO): hash_n = H(prev_hash || "ts:net_w:bucket_j"), hex-encoded
+use serde::Serialize;
+use sha2::Digest;
+
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo { Blake3, Sha256, None }
+
+impl HashAlgo {
+    // what samples carry in `hash_algo`; None when hashing is off
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            HashAlgo::Blake3 => Some("blake3"),
+            HashAlgo::Sha256 => Some("sha256"),
+            HashAlgo::None => None,
+        }
+    }
+
+    // empty with HashAlgo::None
+    pub fn chain(self, prev_hash: &str, ts: f64, net_w: f64, bucket_j: f64) -> String {
+        let fields = format!("{}:{}:{}", ts, net_w, bucket_j);
+        match self {
+            HashAlgo::Blake3 => {
+                let mut h = blake3::Hasher::new();
+                h.update(prev_hash.as_bytes());
+                h.update(fields.as_bytes());
+                h.finalize().to_hex().to_string()
+            }
+            HashAlgo::Sha256 => {
+                let mut h = sha2::Sha256::new();
+                h.update(prev_hash.as_bytes());
+                h.update(fields.as_bytes());
+                hex::encode(h.finalize())
+            }
+            HashAlgo::None => String::new(),
+        }
+    }
+}
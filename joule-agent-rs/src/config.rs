@@ -0,0 +1,77 @@
+// Settings lookup: JOULE_* env vars override an optional flat TOML file (--config / JOULE_CONFIG)
+// whose keys are the env names without the prefix, lowercased (JOULE_CPU_TDP_W -> cpu_tdp_w).
+// Precedence: defaults < file < env.
+use parking_lot::RwLock;
+
+// replaced wholesale on SIGHUP (see reload.rs)
+static FILE: RwLock<Option<toml::Table>> = parking_lot::const_rwlock(None);
+
+// path from `--config <path>` / `--config=<path>`, else JOULE_CONFIG
+pub fn path_from_args() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(a) = args.next() {
+        if a == "--config" { return args.next(); }
+        if let Some(p) = a.strip_prefix("--config=") { return Some(p.to_string()); }
+    }
+    std::env::var("JOULE_CONFIG").ok()
+}
+
+pub fn init(path: Option<&str>) -> Result<(), String> {
+    let Some(path) = path else { return Ok(()) };
+    *FILE.write() = Some(parse(path)?);
+    Ok(())
+}
+
+fn parse(path: &str) -> Result<toml::Table, String> {
+    let raw = std::fs::read_to_string(path).map_err(|e| format!("cannot read config {}: {}", path, e))?;
+    let table: toml::Table = raw.parse().map_err(|e| format!("invalid config {}: {}", path, e))?;
+    for (k, v) in &table {
+        if v.is_table() || v.is_array() { return Err(format!("config {}: `{}` must be a plain value", path, k)); }
+    }
+    Ok(table)
+}
+
+// re-read the file and make it current; returns the previous table so a caller that finds the new
+// values invalid can put it back with `restore`
+pub fn reload(path: &str) -> Result<Option<toml::Table>, String> {
+    let table = parse(path)?;
+    Ok(FILE.write().replace(table))
+}
+
+pub fn restore(prev: Option<toml::Table>) {
+    *FILE.write() = prev;
+}
+
+// file keys whose value differs between `prev` and the current table, as JOULE_* names
+pub fn changed_keys(prev: &Option<toml::Table>) -> Vec<String> {
+    let empty = toml::Table::new();
+    let cur = FILE.read();
+    let (old, new) = (prev.as_ref().unwrap_or(&empty), cur.as_ref().unwrap_or(&empty));
+    let mut keys: Vec<String> = old.keys().chain(new.keys())
+        .filter(|k| old.get(*k) != new.get(*k))
+        .map(|k| format!("JOULE_{}", k.to_ascii_uppercase()))
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+// raw string value of a setting, env first
+pub fn lookup(key: &str) -> Option<String> {
+    if let Ok(v) = std::env::var(key) { return Some(v); }
+    let file_key = key.strip_prefix("JOULE_").unwrap_or(key).to_ascii_lowercase();
+    match FILE.read().as_ref()?.get(&file_key)? {
+        toml::Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+pub fn env_f(key: &str, def: f64) -> f64 {
+    lookup(key).and_then(|v| v.trim().parse().ok()).unwrap_or(def)
+}
+pub fn env_b(key: &str) -> bool {
+    matches!(lookup(key).as_deref(), Some("1") | Some("true") | Some("yes"))
+}
+pub fn env_opt_f(key: &str) -> Option<f64> {
+    lookup(key).and_then(|v| v.trim().parse().ok())
+}
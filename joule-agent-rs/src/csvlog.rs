@@ -0,0 +1,58 @@
+// Append-only CSV energy log (JOULE_CSV_PATH) with size-based rotation
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+const HEADER: &str = "ts,gpu_w,cpu_w,compute_net_w,idle_gpu_w,idle_cpu_w,bucket_j";
+
+pub struct CsvLog {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    out: BufWriter<File>,
+    written: u64,
+}
+
+pub struct Row { pub ts: f64, pub gpu_w: f64, pub cpu_w: f64, pub compute_net_w: f64, pub idle_gpu_w: f64, pub idle_cpu_w: f64, pub bucket_j: f64 }
+
+impl CsvLog {
+    pub fn open(path: &Path, max_mb: Option<f64>) -> std::io::Result<CsvLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        let mut log = CsvLog {
+            path: path.to_path_buf(),
+            max_bytes: max_mb.map(|mb| (mb * 1024.0 * 1024.0) as u64),
+            out: BufWriter::new(file),
+            written,
+        };
+        if written == 0 { log.write_line(HEADER)?; }
+        Ok(log)
+    }
+
+    pub fn append(&mut self, r: &Row) -> std::io::Result<()> {
+        if self.max_bytes.is_some_and(|max| self.written >= max) { self.rotate()?; }
+        let line = format!("{},{},{},{},{},{},{}", r.ts, r.gpu_w, r.cpu_w, r.compute_net_w, r.idle_gpu_w, r.idle_cpu_w, r.bucket_j);
+        self.write_line(&line)
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> { self.out.flush() }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        writeln!(self.out, "{}", line)?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    // current file -> <path>.<UTC timestamp>, then start a fresh file with a header
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.out.flush()?;
+        let mut rotated = self.path.as_os_str().to_owned();
+        rotated.push(format!(".{}", chrono::Utc::now().format("%Y%m%dT%H%M%S%3f")));
+        std::fs::rename(&self.path, PathBuf::from(rotated))?;
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.out = BufWriter::new(file);
+        self.written = 0;
+        self.write_line(HEADER)
+    }
+}
@@ -0,0 +1,70 @@
+// Sampler loop timing (GET /v1/diag): whether sampling keeps up with its period on a loaded machine
+use crate::affinity::Placement;
+use serde::Serialize;
+use std::{collections::{BTreeMap, VecDeque}, time::Duration};
+
+// inter-tick intervals averaged over this many ticks
+const INTERVAL_WINDOW: usize = 60;
+
+#[derive(Default)]
+pub struct Diag {
+    ticks: u64,
+    overruns: u64, // ticks whose processing alone took longer than the period
+    last_loop: Duration,
+    last_wait: Duration,
+    period: Duration,
+    intervals: VecDeque<f64>, // seconds between consecutive tick starts, oldest first
+    sources: Vec<(&'static str, Duration)>, // per power source, last tick
+    placement: Placement,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct Report {
+    pub ticks: u64,
+    pub overruns: u64,
+    pub period_ms: f64,
+    pub last_loop_ms: f64,
+    pub last_wait_ms: f64,
+    // null until two ticks have run
+    pub avg_interval_ms: Option<f64>,
+    // time spent in the NVML source last tick; null without NVML
+    pub nvml_latency_ms: Option<f64>,
+    pub source_latency_ms: BTreeMap<&'static str, f64>,
+    // where the sampler runs (JOULE_SAMPLER_CPU / JOULE_SAMPLER_PRIORITY), as it took effect
+    pub sampler: Placement,
+}
+
+fn ms(d: Duration) -> f64 { d.as_secs_f64() * 1000.0 }
+
+impl Diag {
+    // `interval` is the real time since the previous tick start, None on the first tick
+    pub fn record(&mut self, interval: Option<Duration>, period: Duration, slip: Duration, wait: Duration, sources: Vec<(&'static str, Duration)>) {
+        self.ticks += 1;
+        if slip > period { self.overruns += 1; }
+        self.last_loop = slip;
+        self.last_wait = wait;
+        self.period = period;
+        if let Some(i) = interval {
+            if self.intervals.len() >= INTERVAL_WINDOW { self.intervals.pop_front(); }
+            self.intervals.push_back(i.as_secs_f64());
+        }
+        self.sources = sources;
+    }
+
+    pub fn placed(&mut self, placement: Placement) { self.placement = placement; }
+
+    pub fn report(&self) -> Report {
+        let n = self.intervals.len();
+        Report {
+            ticks: self.ticks,
+            overruns: self.overruns,
+            period_ms: ms(self.period),
+            last_loop_ms: ms(self.last_loop),
+            last_wait_ms: ms(self.last_wait),
+            avg_interval_ms: (n > 0).then(|| self.intervals.iter().sum::<f64>() / n as f64 * 1000.0),
+            nvml_latency_ms: self.sources.iter().find(|(name, _)| *name == "nvml").map(|(_, d)| ms(*d)),
+            source_latency_ms: self.sources.iter().map(|(name, d)| (*name, ms(*d))).collect(),
+            sampler: self.placement,
+        }
+    }
+}
@@ -0,0 +1,50 @@
+// Duty cycle: how much of the time compute_net_w was above JOULE_ACTIVE_W, over a rolling
+// JOULE_DUTY_WINDOW_S and in total since start. Time-weighted, so uneven ticks count by length
+use std::collections::VecDeque;
+
+pub struct Duty {
+    threshold_w: f64,
+    window_s: f64,
+    ticks: VecDeque<(f64, f64, bool)>, // (end, seconds, active), oldest first; ends on the monotonic clock
+    window_active_s: f64,
+    window_total_s: f64,
+    evicted: usize,
+    active_s: f64,
+    idle_s: f64,
+}
+
+impl Duty {
+    pub fn new(threshold_w: f64, window_s: f64) -> Duty {
+        Duty { threshold_w, window_s, ticks: VecDeque::new(), window_active_s: 0.0, window_total_s: 0.0, evicted: 0, active_s: 0.0, idle_s: 0.0 }
+    }
+
+    // one sampler tick of `dt` seconds ending at `now` (seconds since start)
+    pub fn push(&mut self, now: f64, dt: f64, net_w: f64) {
+        if !dt.is_finite() || dt <= 0.0 { return; }
+        let active = net_w > self.threshold_w;
+        if active { self.active_s += dt } else { self.idle_s += dt }
+        self.ticks.push_back((now, dt, active));
+        self.window_total_s += dt;
+        if active { self.window_active_s += dt; }
+        while self.ticks.front().is_some_and(|&(end, _, _)| end <= now - self.window_s) {
+            let (_, dt, active) = self.ticks.pop_front().unwrap_or_default();
+            self.window_total_s -= dt;
+            if active { self.window_active_s -= dt; }
+            self.evicted += 1;
+        }
+        // recount once per window's worth of evictions so float drift can't accumulate
+        if self.evicted >= self.ticks.len() {
+            self.window_total_s = self.ticks.iter().map(|t| t.1).sum();
+            self.window_active_s = self.ticks.iter().filter(|t| t.2).map(|t| t.1).sum();
+            self.evicted = 0;
+        }
+    }
+
+    // 0..100 over the window; None before the first tick
+    pub fn pct(&self) -> Option<f64> {
+        (self.window_total_s > 0.0).then(|| (self.window_active_s / self.window_total_s * 100.0).clamp(0.0, 100.0))
+    }
+
+    pub fn active_s(&self) -> f64 { self.active_s }
+    pub fn idle_s(&self) -> f64 { self.idle_s }
+}
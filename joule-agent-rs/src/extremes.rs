@@ -0,0 +1,50 @@
+// Lifetime power extremes (the sample's `extremes`, /metrics, POST /v1/extremes/reset): the
+// highest and lowest gpu_w, cpu_w and compute_net_w seen, with the Unix time of each
+use crate::metrics::Metric;
+use serde::Serialize;
+
+#[derive(Clone, Copy, Default, Serialize, schemars::JsonSchema, utoipa::ToSchema)]
+pub struct Extremes {
+    pub gpu_w_max: Option<f64>,
+    pub gpu_w_max_ts: Option<f64>,
+    pub gpu_w_min: Option<f64>,
+    pub gpu_w_min_ts: Option<f64>,
+    pub cpu_w_max: Option<f64>,
+    pub cpu_w_max_ts: Option<f64>,
+    pub cpu_w_min: Option<f64>,
+    pub cpu_w_min_ts: Option<f64>,
+    pub net_w_max: Option<f64>,
+    pub net_w_max_ts: Option<f64>,
+    pub net_w_min: Option<f64>,
+    pub net_w_min_ts: Option<f64>,
+}
+
+// ties keep the first time the value was seen
+fn track(w: f64, ts: f64, max: &mut Option<f64>, max_ts: &mut Option<f64>, min: &mut Option<f64>, min_ts: &mut Option<f64>) {
+    if !w.is_finite() { return; }
+    if max.is_none_or(|m| w > m) { *max = Some(w); *max_ts = Some(ts); }
+    if min.is_none_or(|m| w < m) { *min = Some(w); *min_ts = Some(ts); }
+}
+
+impl Extremes {
+    pub fn push(&mut self, ts: f64, gpu_w: f64, cpu_w: f64, net_w: f64) {
+        track(gpu_w, ts, &mut self.gpu_w_max, &mut self.gpu_w_max_ts, &mut self.gpu_w_min, &mut self.gpu_w_min_ts);
+        track(cpu_w, ts, &mut self.cpu_w_max, &mut self.cpu_w_max_ts, &mut self.cpu_w_min, &mut self.cpu_w_min_ts);
+        track(net_w, ts, &mut self.net_w_max, &mut self.net_w_max_ts, &mut self.net_w_min, &mut self.net_w_min_ts);
+    }
+
+    // nothing until the first tick after start or a reset
+    pub fn gauges(&self) -> Vec<Metric> {
+        [
+            ("cryoflux_gpu_watts_max", "Highest GPU power seen since start or the last reset", self.gpu_w_max),
+            ("cryoflux_gpu_watts_min", "Lowest GPU power seen since start or the last reset", self.gpu_w_min),
+            ("cryoflux_cpu_watts_max", "Highest CPU power seen since start or the last reset", self.cpu_w_max),
+            ("cryoflux_cpu_watts_min", "Lowest CPU power seen since start or the last reset", self.cpu_w_min),
+            ("cryoflux_net_watts_max", "Highest compute power above idle seen since start or the last reset", self.net_w_max),
+            ("cryoflux_net_watts_min", "Lowest compute power above idle seen since start or the last reset", self.net_w_min),
+        ]
+        .into_iter()
+        .filter_map(|(name, help, v)| v.map(|v| Metric::gauge(name, help, v)))
+        .collect()
+    }
+}
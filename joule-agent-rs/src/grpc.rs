@@ -0,0 +1,119 @@
+// gRPC server mirroring the HTTP API (JOULE_GRPC_PORT, cargo feature "grpc"); proto/cryoflux.proto
+// tonic::Status is large, but it is the error type the generated service trait demands
+#![allow(clippy::result_large_err)]
+use crate::{bearer_token, check_joules, check_warm, constant_time_eq, rate_client, shutdown_requested, ApiError, Last, State, DEFAULT_BUCKET};
+use std::{net::SocketAddr, pin::Pin};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tonic::{transport::Server, Request, Response, Status};
+
+pub mod pb { tonic::include_proto!("cryoflux.v1"); }
+
+use pb::joule_agent_server::{JouleAgent, JouleAgentServer};
+
+struct Svc { st: State }
+
+impl From<&Last> for pb::Sample {
+    fn from(s: &Last) -> Self {
+        pb::Sample {
+            ts: s.ts,
+            mono_ts: s.mono_ts,
+            host: s.host.to_string(),
+            machine_id: s.machine_id.to_string(),
+            gpu_w: s.gpu_w,
+            per_gpu_w: s.per_gpu_w.clone(),
+            cpu_w: s.cpu_w,
+            cpu_source: s.cpu_source.to_string(),
+            idle_gpu_w: s.idle_gpu_w,
+            idle_cpu_w: s.idle_cpu_w,
+            wall_w: s.wall_w,
+            compute_net_w: s.compute_net_w,
+            network_w: s.network_w,
+            bucket_j: s.bucket_j,
+            buckets: s.buckets.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            spilled_j: s.spilled_j,
+            total_integrated_j: s.total_integrated_j,
+            warmup: s.warmup,
+            warming_up: s.warming_up,
+            warmup_remaining_s: s.warmup_remaining_s,
+            cost_usd: s.cost_usd,
+            co2_g: s.co2_g,
+            hash: s.hash.clone(),
+            prev_hash: s.prev_hash.clone(),
+            hash_algo: s.hash_algo.map(String::from),
+            sig: s.sig.clone(),
+            power_sources: s.power_sources.iter().map(|n| n.to_string()).collect(),
+        }
+    }
+}
+
+impl Svc {
+    // same rules as the HTTP require_token: take/refund always, reads only with JOULE_PROTECT_READS
+    fn authorize<T>(&self, req: &Request<T>, mutating: bool) -> Result<(), Status> {
+        let Some(token) = &self.st.cfg.api_token else { return Ok(()) };
+        if !mutating && !self.st.cfg.protect_reads { return Ok(()); }
+        match bearer_token(&req.metadata().clone().into_headers()) {
+            Some(p) if constant_time_eq(p.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => Err(Status::unauthenticated("missing or invalid bearer token")),
+        }
+    }
+}
+
+fn bucket_or_default(bucket: String) -> String {
+    if bucket.is_empty() { DEFAULT_BUCKET.to_string() } else { bucket }
+}
+
+fn invalid(e: ApiError) -> Status {
+    Status::invalid_argument(e.message)
+}
+
+#[tonic::async_trait]
+impl JouleAgent for Svc {
+    async fn get_sample(&self, req: Request<pb::GetSampleRequest>) -> Result<Response<pb::Sample>, Status> {
+        self.authorize(&req, false)?;
+        Ok(Response::new(pb::Sample::from(&*self.st.last.lock())))
+    }
+
+    async fn take(&self, req: Request<pb::TakeRequest>) -> Result<Response<pb::TakeResponse>, Status> {
+        self.authorize(&req, true)?;
+        // the same per-client budget as POST /v1/take, keyed by the valid token or peer address
+        if let Some(limiter) = &self.st.take_limiter {
+            let peer = req.remote_addr().unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+            if let Err(wait) = limiter.check(rate_client(&self.st, &req.metadata().clone().into_headers(), peer)) {
+                return Err(Status::resource_exhausted(format!("rate limit exceeded, retry in {:.1}s", wait.as_secs_f64())));
+            }
+        }
+        check_warm(&self.st).map_err(|e| Status::unavailable(e.message))?;
+        let req = req.into_inner();
+        check_joules(req.joules).map_err(invalid)?;
+        let r = self.st.take(&bucket_or_default(req.bucket), req.joules, req.allow_partial);
+        Ok(Response::new(pb::TakeResponse { ok: r.ok, remaining_j: r.remaining_j, granted_j: r.granted_j }))
+    }
+
+    async fn refund(&self, req: Request<pb::RefundRequest>) -> Result<Response<pb::RefundResponse>, Status> {
+        self.authorize(&req, true)?;
+        let req = req.into_inner();
+        check_joules(req.joules).map_err(invalid)?;
+        let r = self.st.refund(&bucket_or_default(req.bucket), req.joules);
+        Ok(Response::new(pb::RefundResponse { ok: r.ok, remaining_j: r.remaining_j }))
+    }
+
+    type StreamSamplesStream = Pin<Box<dyn Stream<Item = Result<pb::Sample, Status>> + Send>>;
+
+    async fn stream_samples(&self, req: Request<pb::StreamSamplesRequest>) -> Result<Response<Self::StreamSamplesStream>, Status> {
+        self.authorize(&req, false)?;
+        // lagged receivers skip what they missed; streams end on shutdown so it isn't held up
+        let samples = BroadcastStream::new(self.st.samples_tx.subscribe())
+            .filter_map(|s| s.ok())
+            .map(|s| Ok(pb::Sample::from(&s)));
+        let samples = futures_util::StreamExt::take_until(samples, shutdown_requested(self.st.shutdown.clone()));
+        Ok(Response::new(Box::pin(samples)))
+    }
+}
+
+pub async fn serve(st: State, addr: SocketAddr) {
+    let shutdown = shutdown_requested(st.shutdown.clone());
+    let svc = JouleAgentServer::new(Svc { st });
+    if let Err(e) = Server::builder().add_service(svc).serve_with_shutdown(addr, shutdown).await {
+        tracing::error!(%addr, error = %e, "gRPC server failed");
+    }
+}
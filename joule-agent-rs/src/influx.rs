@@ -0,0 +1,81 @@
+// InfluxDB v2 line-protocol push (JOULE_INFLUX_URL + JOULE_INFLUX_TOKEN)
+use crate::{shutdown_requested, Last, State};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+pub struct InfluxCfg {
+    pub url: String,
+    pub token: String,
+    pub org: String,
+    pub bucket: String,
+    pub flush_s: f64,
+    pub max_retries: u32,
+}
+
+// tag values escape commas, spaces and equals signs
+fn escape_tag(v: &str) -> String {
+    v.replace(',', "\\,").replace(' ', "\\ ").replace('=', "\\=")
+}
+
+fn line(s: &Last) -> String {
+    format!(
+        "cryoflux,host={},machine_id={} gpu_w={},cpu_w={},compute_net_w={},network_w={},bucket_j={} {}",
+        escape_tag(&s.host), escape_tag(&s.machine_id), s.gpu_w, s.cpu_w, s.compute_net_w, s.network_w, s.bucket_j,
+        (s.wall_ts * 1e3).round() as i64 * 1_000_000, // millisecond resolution; Unix time whatever JOULE_CLOCK says
+    )
+}
+
+pub async fn run(st: State, cfg: InfluxCfg) {
+    let client = reqwest::Client::new();
+    let endpoint = format!("{}/api/v2/write", cfg.url.trim_end_matches('/'));
+    let mut rx = st.samples_tx.subscribe();
+    let mut flush = tokio::time::interval(Duration::from_secs_f64(cfg.flush_s.max(0.1)));
+    let mut batch: Vec<String> = Vec::new();
+    loop {
+        tokio::select! {
+            r = rx.recv() => match r {
+                Ok(s) => batch.push(line(&s)),
+                Err(RecvError::Lagged(n)) => tracing::warn!(missed = n, "influx push fell behind"),
+                Err(RecvError::Closed) => break,
+            },
+            _ = flush.tick() => {
+                if !batch.is_empty() { push(&client, &endpoint, &cfg, &std::mem::take(&mut batch)).await; }
+            }
+            _ = shutdown_requested(st.shutdown.clone()) => break,
+        }
+    }
+    if !batch.is_empty() { push(&client, &endpoint, &cfg, &batch).await; }
+}
+
+// retry transient failures (network, 429, 5xx) with exponential backoff; drop the batch after
+// max_retries so a dead server can't make it grow without bound
+async fn push(client: &reqwest::Client, endpoint: &str, cfg: &InfluxCfg, batch: &[String]) {
+    let body = batch.join("\n");
+    let mut backoff = Duration::from_millis(500);
+    for attempt in 0..=cfg.max_retries {
+        let res = client
+            .post(endpoint)
+            .query(&[("org", cfg.org.as_str()), ("bucket", cfg.bucket.as_str()), ("precision", "ns")])
+            .header("Authorization", format!("Token {}", cfg.token))
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(body.clone())
+            .send()
+            .await;
+        let transient = match res {
+            Ok(r) if r.status().is_success() => return,
+            Ok(r) => {
+                let status = r.status();
+                tracing::warn!(%status, attempt, "influx write rejected");
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, attempt, "influx write failed");
+                true
+            }
+        };
+        if !transient || attempt == cfg.max_retries { break; }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(30));
+    }
+    tracing::warn!(points = batch.len(), "dropping influx batch");
+}
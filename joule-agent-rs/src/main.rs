@@ -1,155 +1,2886 @@
-use axum::{routing::{get, post}, Json, Router};
+use axum::{
+    extract::{ws::WebSocketUpgrade, ConnectInfo, FromRequest, Query, Request, State as AxState},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{sse::{Event, KeepAlive, Sse}, Html, IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::Arc, time::{Duration, Instant}};
-use sysinfo::System;
+use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, net::{IpAddr, SocketAddr}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc}, time::{Duration, Instant}};
+use sysinfo::{Networks, ProcessRefreshKind, System};
 use chrono::Utc;
+use config::{env_b, env_f, env_opt_f};
+use tokio::sync::{broadcast, watch};
+use tokio_stream::{wrappers::{errors::BroadcastStreamRecvError, BroadcastStream}, StreamExt};
 
-// NVML (GPU power, optional)
-fn gpu_power_w(nvml: &Option<nvml_wrapper::Nvml>) -> f64 {
-    if let Some(n) = nvml {
-        if let Ok(dev) = n.device_by_index(0) {
-            if let Ok(mw) = dev.power_usage() { return (mw as f64) / 1000.0; }
+mod admit;
+mod affinity;
+mod alert;
+mod carbon;
+mod cgroup;
+mod chain;
+mod config;
+mod csvlog;
+mod diag;
+mod duty;
+mod extremes;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod influx;
+mod metrics;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod openapi;
+#[cfg(feature = "otel")]
+mod otel;
+#[cfg(windows)]
+mod pdh;
+mod persist;
+mod powercap;
+mod replay;
+mod remote_write;
+mod rollup;
+#[cfg(target_os = "macos")]
+mod powermetrics;
+mod ratelimit;
+#[cfg(unix)]
+mod reload;
+mod sign;
+mod sources;
+mod stats;
+mod takehist;
+mod takelog;
+mod throttle;
+mod top;
+mod wallplug;
+mod ws;
+
+// NVML per-device telemetry; each reading is guarded on its own so one unsupported metric
+// doesn't blank the others
+#[derive(Clone, Serialize, schemars::JsonSchema, utoipa::ToSchema)]
+struct GpuStats { index: u32, util_pct: Option<u32>, mem_used_mb: Option<f64>, mem_total_mb: Option<f64>, temp_c: Option<u32>, power_cap_w: Option<f64>, throttle_reasons: Vec<&'static str> }
+
+fn gpu_stats(nvml: &Option<nvml_wrapper::Nvml>, devices: &[u32]) -> Vec<GpuStats> {
+    let Some(n) = nvml else { return Vec::new() };
+    let mb = |b: u64| b as f64 / (1024.0 * 1024.0);
+    devices.iter()
+        .filter_map(|&index| n.device_by_index(index).ok().map(|dev| (index, dev)))
+        .map(|(index, dev)| {
+            let mem = dev.memory_info().ok();
+            GpuStats {
+                index,
+                util_pct: dev.utilization_rates().ok().map(|u| u.gpu),
+                mem_used_mb: mem.as_ref().map(|m| mb(m.used)),
+                mem_total_mb: mem.as_ref().map(|m| mb(m.total)),
+                temp_c: dev.temperature(nvml_wrapper::enum_wrappers::device::TemperatureSensor::Gpu).ok(),
+                power_cap_w: dev.enforced_power_limit().ok().map(|mw| mw as f64 / 1000.0),
+                throttle_reasons: throttle::gpu_reasons(&dev),
+            }
+        })
+        .collect()
+}
+
+// NVIDIA devices to meter: JOULE_GPU_INCLUDE (default all) minus JOULE_GPU_EXCLUDE, each a list of
+// indices or NVML UUIDs. Naming a device that doesn't exist is a startup error
+fn select_gpus(n: &nvml_wrapper::Nvml, include: &[String], exclude: &[String]) -> Result<Vec<u32>, String> {
+    let count = n.device_count().map_err(|e| format!("cannot count NVML devices: {}", e))?;
+    let uuids: Vec<Option<String>> = (0..count).map(|i| n.device_by_index(i).and_then(|d| d.uuid()).ok()).collect();
+    let resolve = |key: &str, item: &String| -> Result<u32, String> {
+        match item.parse::<u32>() {
+            Ok(i) if i < count => Ok(i),
+            Ok(i) => Err(format!("{}: no GPU with index {} ({} devices)", key, i, count)),
+            Err(_) => uuids.iter().position(|u| u.as_deref() == Some(item.as_str())).map(|i| i as u32)
+                .ok_or_else(|| format!("{}: no GPU with UUID {:?}", key, item)),
         }
+    };
+    let included: Vec<u32> = if include.is_empty() { (0..count).collect() } else {
+        include.iter().map(|i| resolve("JOULE_GPU_INCLUDE", i)).collect::<Result<_, _>>()?
+    };
+    let excluded: Vec<u32> = exclude.iter().map(|i| resolve("JOULE_GPU_EXCLUDE", i)).collect::<Result<_, _>>()?;
+    let mut selected: Vec<u32> = included.into_iter().filter(|i| !excluded.contains(i)).collect();
+    selected.sort_unstable();
+    selected.dedup();
+    Ok(selected)
+}
+
+fn parse_list(raw: &str) -> Vec<String> {
+    raw.split(',').map(str::trim).filter(|i| !i.is_empty()).map(String::from).collect()
+}
+
+// Battery discharge (whole-system power on laptops, Linux power_supply sysfs)
+#[derive(Clone, Copy)]
+struct Battery { watts: f64, on_ac: bool }
+
+fn battery_power() -> Option<Battery> {
+    let mut found = false;
+    let mut watts = 0.0;
+    let mut discharging = false;
+    for entry in std::fs::read_dir("/sys/class/power_supply").ok()?.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with("BAT") { continue; }
+        let dir = entry.path();
+        found = true;
+        // power_now is in µW; some firmware only exposes voltage_now (µV) * current_now (µA)
+        let w = match read_u64(&dir.join("power_now")) {
+            Some(uw) => uw as f64 / 1e6,
+            None => match (read_u64(&dir.join("voltage_now")), read_u64(&dir.join("current_now"))) {
+                (Some(uv), Some(ua)) => uv as f64 * ua as f64 / 1e12,
+                _ => 0.0,
+            },
+        };
+        let status = std::fs::read_to_string(dir.join("status")).unwrap_or_default();
+        if status.trim() == "Discharging" && w > 0.0 { discharging = true; watts += w; }
     }
-    0.0
+    if !found { return None; }
+    Some(Battery { watts, on_ac: !discharging })
 }
 
-#[derive(Clone)]
-struct Cfg { cpu_tdp_w: f64, smoothing_alpha: f64, hz: f64, idle_learn_w: f64 }
+fn read_u64(path: &Path) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+// live-tunable settings (GET/PUT /v1/config); read by the sampler every tick. hz is the
+// integration rate; publish_hz (None = every tick) thins out the published samples.
+// smoothing_alpha drives the idle baseline EMA, power_smoothing the one over live readings (1 = off)
+#[derive(Clone, Copy, Serialize)]
+struct Tunables {
+    cpu_tdp_w: f64, smoothing_alpha: f64, power_smoothing: f64, hz: f64, publish_hz: Option<f64>,
+    idle_learn_w: f64, idle_learn_frac: f64, idle_learn_mode: IdleLearnMode,
+}
+
+// PUT /v1/config body; absent fields keep their value
+#[derive(Deserialize, utoipa::ToSchema)]
+struct TunablesPatch {
+    cpu_tdp_w: Option<f64>, smoothing_alpha: Option<f64>, power_smoothing: Option<f64>, hz: Option<f64>,
+    /// null goes back to publishing every tick; leaving it out keeps the current rate.
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<f64>)]
+    publish_hz: Option<Option<f64>>,
+    idle_learn_w: Option<f64>, idle_learn_frac: Option<f64>, idle_learn_mode: Option<IdleLearnMode>,
+}
+
+// for Option<Option<T>> fields: Some(None) when the key is there with null, None (via default)
+// when it is absent
+fn present<'de, D: serde::Deserializer<'de>, T: Deserialize<'de>>(d: D) -> Result<Option<T>, D::Error> {
+    T::deserialize(d).map(Some)
+}
+
+// when the idle baselines may learn: net power under idle_learn_w, within idle_learn_frac of the
+// learned idle itself (scales with the machine), or both
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum IdleLearnMode { Absolute, Fraction, Both }
+
+impl Tunables {
+    fn validate(&self) -> Result<(), String> {
+        if !(self.cpu_tdp_w.is_finite() && self.cpu_tdp_w > 0.0) { return Err("cpu_tdp_w must be > 0".into()); }
+        if !(0.0..=1.0).contains(&self.smoothing_alpha) { return Err("smoothing_alpha must be within 0..1".into()); }
+        // 0 would freeze the published power at its first reading
+        if !(self.power_smoothing > 0.0 && self.power_smoothing <= 1.0) { return Err("power_smoothing must be > 0 and <= 1".into()); }
+        if !(self.hz.is_finite() && self.hz > 0.0) { return Err("hz must be > 0".into()); }
+        if let Some(p) = self.publish_hz {
+            if !(p.is_finite() && p > 0.0) { return Err("publish_hz must be > 0".into()); }
+            if p > self.hz { return Err("publish_hz must not exceed the integration hz".into()); }
+        }
+        if !(self.idle_learn_w.is_finite() && self.idle_learn_w >= 0.0) { return Err("idle_learn_w must be >= 0".into()); }
+        if !(self.idle_learn_frac.is_finite() && self.idle_learn_frac >= 0.0) { return Err("idle_learn_frac must be >= 0".into()); }
+        Ok(())
+    }
+    // `net_w` is the power above the learned `idle_w`
+    fn idle_gate(&self, net_w: f64, idle_w: f64) -> bool {
+        let abs = net_w < self.idle_learn_w;
+        let frac = net_w <= self.idle_learn_frac * idle_w;
+        match self.idle_learn_mode {
+            IdleLearnMode::Absolute => abs,
+            IdleLearnMode::Fraction => frac,
+            IdleLearnMode::Both => abs && frac,
+        }
+    }
+    fn apply(&self, p: &TunablesPatch) -> Tunables {
+        Tunables {
+            cpu_tdp_w: p.cpu_tdp_w.unwrap_or(self.cpu_tdp_w),
+            smoothing_alpha: p.smoothing_alpha.unwrap_or(self.smoothing_alpha),
+            power_smoothing: p.power_smoothing.unwrap_or(self.power_smoothing),
+            hz: p.hz.unwrap_or(self.hz),
+            publish_hz: p.publish_hz.unwrap_or(self.publish_hz),
+            idle_learn_w: p.idle_learn_w.unwrap_or(self.idle_learn_w),
+            idle_learn_frac: p.idle_learn_frac.unwrap_or(self.idle_learn_frac),
+            idle_learn_mode: p.idle_learn_mode.unwrap_or(self.idle_learn_mode),
+        }
+    }
+}
+
+// plausible range for a learned idle baseline (JOULE_IDLE_MIN_W / JOULE_IDLE_MAX_W, or per side
+// JOULE_IDLE_GPU_MIN_W etc.); an unbounded max serializes as null
+#[derive(Clone, Copy, Serialize)]
+struct IdleBounds { min_w: f64, max_w: f64 }
+
+// clamps each EMA update and counts how many in a row needed it: a baseline pinned to its bound
+// means the bounds or the idle detection are misconfigured
+struct ClampWatch { side: &'static str, bounds: IdleBounds, hits: u32 }
+
+impl ClampWatch {
+    const WARN_AFTER: u32 = 60;
+
+    fn new(side: &'static str, bounds: IdleBounds) -> Self { ClampWatch { side, bounds, hits: 0 } }
+
+    fn apply(&mut self, learned_w: f64) -> f64 {
+        let w = learned_w.clamp(self.bounds.min_w, self.bounds.max_w);
+        if w == learned_w {
+            self.hits = 0;
+            return w;
+        }
+        self.hits += 1;
+        if self.hits == Self::WARN_AFTER {
+            tracing::warn!(side = self.side, learned_w, clamped_w = w, updates = self.hits, "idle baseline keeps hitting its bound; check JOULE_IDLE_MIN_W/MAX_W and the idle-learn thresholds");
+        }
+        w
+    }
+}
+
+#[derive(Clone, Serialize)]
+struct Cfg {
+    net_j_per_byte: f64,
+    reservation_ttl_s: f64,
+    shutdown_reservations: ShutdownReservations,
+    history_len: usize,
+    history_minutes: usize, // per-minute aggregates kept
+    history_hours: usize,
+    compression: bool,
+    take_log_len: usize,
+    // samples held per stream subscriber; a slower one loses the oldest, the sampler never waits
+    stream_buffer: usize,
+    bucket_cap_j: Option<f64>,
+    // share of each integration step credited to each named bucket (normalized); empty = all to default
+    bucket_weights: Vec<(String, f64)>,
+    overflow: Overflow,
+    meter_mode: MeterMode,
+    power_source: PowerMode,
+    gpu_power_method: GpuPowerMethod,
+    clock: ClockMode,
+    hash_algo: chain::HashAlgo,
+    api_version: ApiVersion, // default when the request names none
+    state_file: Option<PathBuf>,
+    state_flush_s: f64,
+    csv_path: Option<PathBuf>,
+    csv_flush_s: f64,
+    csv_max_mb: Option<f64>,
+    #[serde(skip)]
+    api_token: Option<Arc<str>>,
+    protect_reads: bool,
+    gpu_power_cap_w: Option<f64>, // applied to every metered NVIDIA GPU at startup
+    cors_origins: Option<Vec<String>>, // JOULE_CORS_ORIGINS; None = no CORS headers (same-origin only)
+    bind_addr: IpAddr,
+    port: u16,
+    bind_attempts: u32,
+    bind_retry_delay_s: f64,
+    idle_gpu_w: f64, // starting baselines, and what /v1/calibrate/reset restores
+    idle_cpu_w: f64,
+    idle_gpu_bounds: IdleBounds, // learning never moves the baselines outside these
+    idle_cpu_bounds: IdleBounds,
+    calibrate_window_s: f64,
+    replay_file: Option<PathBuf>,
+    replay_loop: bool,
+    socket_tdp_w: Vec<f64>, // JOULE_CPU_TDP_W per socket; splits the estimate, scaled to the live cpu_tdp_w
+    subtract_self: bool, // take agent_self_w out of compute_net_w (and so out of the bucket)
+    dram: bool, // meter RAPL DRAM domains as dram_w
+    docs: bool, // serve the Swagger UI at /docs
+    warmup_s: f64, // after start: takes refused, idle baselines learned from every reading
+    active_w: f64, // compute_net_w above this counts as active time for the duty cycle
+    duty_window_s: f64,
+    sampler_cpu: Option<usize>, // core to pin the sampler thread to
+    sampler_priority: affinity::Priority,
+    suspend_gap_s: f64,
+    stats_windows: Vec<(String, f64)>,
+    take_hist_buckets: Vec<f64>,
+    price_per_kwh: f64,
+    co2_g_per_kwh: f64, // static intensity; fallback when JOULE_CO2_API_URL is unset or failing
+    refill: Option<Refill>,
+    admit_priority_threshold: u8,
+    admit_high_water_j: Option<f64>,
+}
+
+// quota mode (JOULE_REFILL_J + JOULE_REFILL_INTERVAL_S): top the buckets up on a schedule
+#[derive(Clone, Copy, Serialize)]
+struct Refill { joules: f64, interval_s: f64, mode: RefillMode }
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RefillMode { Add, Set }
+
+// net = energy above the learned idle (marginal compute), gross = everything drawn, idle included
+#[derive(Clone, Copy, Default, PartialEq, Serialize, schemars::JsonSchema, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum MeterMode { #[default] Net, Gross }
+
+// what happens to joules integrated past JOULE_BUCKET_CAP_J
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Overflow { Clamp, Spill }
+
+// what graceful shutdown does with held reservations: release = back into their buckets before
+// the final flush, keep = into the state file with their expiry, restored on the next start
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ShutdownReservations { Release, Keep }
+
+// primary CPU-side source; auto = RAPL, else the TDP estimate
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum PowerMode { Auto, Battery }
+
+// how NVIDIA GPU power is read: instant = power_usage() each tick, energy_counter = the
+// total_energy_consumption() delta, buffer = the mean of the driver's power samples since the
+// last tick; the last two fall back to power_usage() where unsupported
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum GpuPowerMethod { Instant, EnergyCounter, Buffer }
+
+// what the sample ts is: wall = Unix time, mono = seconds since start (immune to clock steps),
+// both = Unix ts plus mono_ts, with history `since` matched against mono_ts
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ClockMode { Wall, Mono, Both }
+
+// sample schema served by /v1/sample, /v1/history and /v1/stream: v1 is the original payload
+// (net_w, no extra fields) for old parsers, v2 the current one
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum ApiVersion { V1, V2 }
+
+// unit of the energy fields in /v1/sample (?units=); power stays in watts
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum EnergyUnit { #[default] J, Wh, Kwh }
+
+impl EnergyUnit {
+    fn joules_per_unit(self) -> f64 {
+        match self { EnergyUnit::J => 1.0, EnergyUnit::Wh => J_PER_WH, EnergyUnit::Kwh => J_PER_KWH }
+    }
+}
 
 #[derive(Default, Clone)]
 struct Last {
-    ts: f64,
+    ts: f64, // per JOULE_CLOCK
+    mono_ts: Option<f64>, // JOULE_CLOCK=both only
+    wall_ts: f64, // always Unix time, for sinks that need absolute timestamps
+    host: Arc<str>,
+    machine_id: Arc<str>,
     gpu_w: f64,
+    gpu_w_raw: f64, // before JOULE_POWER_SMOOTHING; equals gpu_w with it off
+    per_gpu_w: Vec<f64>,
+    gpu_vendors: Vec<&'static str>, // detected at startup: "nvidia", "amd"
+    gpu_devices: Vec<u32>, // NVML indices metered, per JOULE_GPU_INCLUDE/JOULE_GPU_EXCLUDE
+    nvml_healthy: bool,
+    per_gpu_stats: Vec<GpuStats>, // NVIDIA devices only
+    throttle_reasons: Vec<String>, // "gpu<index>:<reason>", "cpu:thermal"
     cpu_w: f64,
+    cpu_w_raw: f64,
+    per_socket_w: Vec<f64>, // sums to cpu_w
+    per_core_usage: Vec<f32>, // 0..100 per logical core
+    per_core_w: Vec<f64>,     // cpu_w split by per-core usage; sums to cpu_w
     idle_gpu_w: f64,
     idle_cpu_w: f64,
-    net_w: f64,
-    bucket_j: f64,
+    wall_w: Option<f64>, // smart-plug reading; None without a (live) plug
+    idle_wall_w: Option<f64>,
+    dram_w: Option<f64>, // RAPL DRAM domains with JOULE_DRAM; None otherwise
+    idle_dram_w: Option<f64>,
+    model_error_w: Option<f64>, // wall_w - (gpu_w + cpu_w)
+    compute_net_w: f64, // power above idle (gpu + cpu, or wall when a plug is live)
+    metered_w: f64,     // what the bucket integrates: compute_net_w, or gross power per JOULE_METER_MODE
+    meter_mode: MeterMode,
+    network_w: f64,     // estimated NIC power from traffic
+    agent_self_w: f64,  // this process's share of cpu_w above idle
+    bucket_j: f64, // total across all buckets
+    buckets: BTreeMap<String, f64>,
+    spilled_j: f64,
+    total_integrated_j: f64, // lifetime, never decremented by takes or refunds
+    cpu_source: &'static str,
+    cgroup_aware: bool, // CPU usage came from the cgroup's quota rather than the host
+    power_sources: Vec<&'static str>, // sources that contributed to this sample
+    power_quality: sources::Quality,
+    warmup: bool,
+    paused: bool,
+    warming_up: bool, // within JOULE_WARMUP_S of start
+    warmup_remaining_s: f64,
+    active_duty_pct: Option<f64>, // share of the last JOULE_DUTY_WINDOW_S above JOULE_ACTIVE_W
+    active_s: f64,
+    idle_s: f64,
+    extremes: extremes::Extremes,
+    suspend_gaps: u64, // ticks skipped as suspend/resume since start
+    period_s: f64, // nominal time between published samples
+    actual_dt_s: Option<f64>, // measured since the previous published sample; None on the first
+    battery_w: Option<f64>, // None without a battery
+    on_ac: Option<bool>,
+    refill_in_s: Option<f64>, // quota mode only
+    cost_usd: f64, // cumulative since start
+    co2_g: f64,    // cumulative since start
+    cost_rate_usd_per_h: f64,
+    co2_g_per_kwh: f64, // intensity in effect for this sample
+    stats: serde_json::Map<String, serde_json::Value>, // rolling compute_net_w avg/peak per window
+    hash: String, // both empty with JOULE_HASH_ALGO=none
+    prev_hash: String,
+    hash_algo: Option<&'static str>,
+    sig: Option<String>,
 }
 // keep last sample in shared Arc so it can be sent across tasks
 
 #[derive(Clone)]
 struct State {
     cfg: Cfg,
-    bucket_j: Arc<Mutex<f64>>,
+    tune: Arc<Mutex<Tunables>>,
+    cpu_curve: sources::SharedCurve, // replaces the linear TDP model when set
+    buckets: Arc<Mutex<HashMap<String, f64>>>,
+    spilled_j: Arc<Mutex<f64>>,
     idle_gpu_w: Arc<Mutex<f64>>,
     idle_cpu_w: Arc<Mutex<f64>>,
+    idle_wall_w: Arc<Mutex<Option<f64>>>, // seeded from the first plug reading
+    idle_dram_w: Arc<Mutex<Option<f64>>>, // seeded from the first DRAM reading
+    wall_w: Arc<Mutex<Option<f64>>>,      // latest smart-plug reading, held between polls
     last: Arc<Mutex<Last>>,
+    history: Arc<Mutex<VecDeque<Last>>>,
+    history_seq: Arc<AtomicU64>, // samples ever appended to history, bumped under its lock; the front is seq - len
+    rollups: Arc<Mutex<rollup::Rollups>>, // minute/hour aggregates behind /v1/history?resolution=
+    take_granted: Arc<AtomicU64>,
+    take_denied: Arc<AtomicU64>,
+    take_hist: Arc<Mutex<takehist::TakeHist>>, // own lock, so recording never waits on the buckets
+    extremes: Arc<Mutex<extremes::Extremes>>,
+    credited: Arc<tokio::sync::Notify>, // woken whenever joules land in a bucket; waiting takes re-check
+    take_log: Arc<Mutex<takelog::TakeLog>>,
+    diag: Arc<Mutex<diag::Diag>>,
+    samples_tx: broadcast::Sender<Last>,
+    reservations: Arc<Mutex<HashMap<u64, Reservation>>>,
+    next_reservation: Arc<AtomicU64>,
+    signer: Option<Arc<ed25519_dalek::SigningKey>>,
+    total_integrated_j: Arc<Mutex<f64>>, // lifetime joules, never decremented
+    co2_g_per_kwh: Arc<Mutex<f64>>,      // refreshed by the carbon-intensity task when configured
+    started: Instant,
+    host: Arc<str>,       // resolved once at startup
+    machine_id: Arc<str>, // JOULE_NODE_ID, else /etc/machine-id, else the hostname
+    last_tick: Arc<Mutex<Instant>>, // when the sampler last published
+    take_limiter: Option<Arc<ratelimit::Limiter>>,
+    next_refill: Arc<Mutex<Option<Instant>>>,
+    paused: Arc<AtomicBool>, // POST /v1/pause: sensors still read, nothing integrated or learned
+    shutdown: watch::Receiver<bool>,
+}
+
+// joules held out of the bucket until committed, released or expired
+struct Reservation { bucket: String, joules: f64, expires: Instant }
+
+const DEFAULT_BUCKET: &str = "default";
+const J_PER_WH: f64 = 3600.0;
+const J_PER_KWH: f64 = 3.6e6;
+// longest a take may wait for energy (TakeReq.wait_ms); longer requests are clamped
+const MAX_TAKE_WAIT: Duration = Duration::from_secs(60);
+// samples (or periods) /v1/export copies out per lock
+const EXPORT_CHUNK: usize = 256;
+
+impl State {
+    // fresh state for `cfg`, seeded with whatever the state file restored; buckets start empty
+    fn new(
+        cfg: Cfg, tune: Tunables, cpu_curve: Option<Vec<(f64, f64)>>, restored: Option<&persist::Persisted>,
+        host: Arc<str>, machine_id: Arc<str>, shutdown_rx: watch::Receiver<bool>,
+    ) -> State {
+        State {
+            cfg: cfg.clone(),
+            tune: Arc::new(Mutex::new(tune)),
+            cpu_curve: Arc::new(Mutex::new(cpu_curve)),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            spilled_j: Arc::new(Mutex::new(0.0)),
+            idle_gpu_w: Arc::new(Mutex::new(restored.map_or(cfg.idle_gpu_w, |p| p.idle_gpu_w))),
+            idle_cpu_w: Arc::new(Mutex::new(restored.map_or(cfg.idle_cpu_w, |p| p.idle_cpu_w))),
+            idle_wall_w: Arc::new(Mutex::new(restored.and_then(|p| p.idle_wall_w))),
+            idle_dram_w: Arc::new(Mutex::new(restored.and_then(|p| p.idle_dram_w))),
+            wall_w: Arc::new(Mutex::new(None)),
+            last: Arc::new(Mutex::new(Last { host: host.clone(), machine_id: machine_id.clone(), ..Last::default() })),
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(cfg.history_len))),
+            history_seq: Arc::new(AtomicU64::new(0)),
+            rollups: Arc::new(Mutex::new(rollup::Rollups::new(cfg.history_minutes, cfg.history_hours))),
+            take_granted: Arc::new(AtomicU64::new(0)),
+            take_denied: Arc::new(AtomicU64::new(0)),
+            take_hist: Arc::new(Mutex::new(takehist::TakeHist::new(cfg.take_hist_buckets.clone()))),
+            credited: Arc::new(tokio::sync::Notify::new()),
+            extremes: Arc::new(Mutex::new(extremes::Extremes::default())),
+            take_log: Arc::new(Mutex::new(takelog::TakeLog::new(cfg.take_log_len))),
+            diag: Arc::new(Mutex::new(diag::Diag::default())),
+            samples_tx: broadcast::channel(cfg.stream_buffer).0,
+            reservations: Arc::new(Mutex::new(HashMap::new())),
+            next_reservation: Arc::new(AtomicU64::new(1)),
+            signer: config::lookup("JOULE_SIGN_KEY").and_then(|raw| match sign::load_key(&raw) {
+                Ok(k) => Some(Arc::new(k)),
+                Err(e) => {
+                    tracing::warn!(error = %e, "JOULE_SIGN_KEY ignored");
+                    None
+                }
+            }),
+            total_integrated_j: Arc::new(Mutex::new(restored.map_or(0.0, |p| p.total_integrated_j))),
+            co2_g_per_kwh: Arc::new(Mutex::new(cfg.co2_g_per_kwh)),
+            started: Instant::now(),
+            host,
+            machine_id,
+            last_tick: Arc::new(Mutex::new(Instant::now())),
+            next_refill: Arc::new(Mutex::new(cfg.refill.map(|r| Instant::now() + Duration::from_secs_f64(r.interval_s)))),
+            take_limiter: env_opt_f("JOULE_TAKE_RPS").filter(|r| r.is_finite() && *r > 0.0).map(|r| Arc::new(ratelimit::Limiter::new(r))),
+            paused: Arc::new(AtomicBool::new(false)),
+            shutdown: shutdown_rx,
+        }
+    }
+
+    // seconds of JOULE_WARMUP_S still to go, 0 once it is over
+    fn warmup_left_s(&self) -> f64 {
+        (self.cfg.warmup_s - self.started.elapsed().as_secs_f64()).max(0.0)
+    }
+
+    // add joules to a locked bucket, applying the configured cap and overflow policy
+    fn credit(&self, b: &mut f64, joules: f64) {
+        *b += joules;
+        if let Some(cap) = self.cfg.bucket_cap_j {
+            if *b > cap {
+                if self.cfg.overflow == Overflow::Spill { *self.spilled_j.lock() += *b - cap; }
+                *b = cap;
+            }
+        }
+        // integration, refills, refunds and released reservations all come through here
+        if joules > 0.0 { self.credited.notify_waiters(); }
+    }
+    // split one integration step across the configured buckets
+    fn integrate(&self, buckets: &mut HashMap<String, f64>, joules: f64) {
+        if self.cfg.bucket_weights.is_empty() {
+            self.credit(buckets.entry(DEFAULT_BUCKET.to_string()).or_insert(0.0), joules);
+        }
+        for (name, w) in &self.cfg.bucket_weights {
+            self.credit(buckets.entry(name.clone()).or_insert(0.0), joules * w);
+        }
+    }
+    // fraction of each integration step / refill that lands in `bucket`
+    fn bucket_share(&self, bucket: &str) -> f64 {
+        if self.cfg.bucket_weights.is_empty() { return if bucket == DEFAULT_BUCKET { 1.0 } else { 0.0 }; }
+        self.cfg.bucket_weights.iter().filter(|(n, _)| n == bucket).map(|(_, w)| w).sum()
+    }
+    // replace the balances with `joules`, split like integration (the cap still applies)
+    fn set_total(&self, buckets: &mut HashMap<String, f64>, joules: f64) {
+        if self.cfg.bucket_weights.is_empty() {
+            let b = buckets.entry(DEFAULT_BUCKET.to_string()).or_insert(0.0);
+            *b = 0.0;
+            self.credit(b, joules);
+        }
+        for (name, w) in &self.cfg.bucket_weights {
+            let b = buckets.entry(name.clone()).or_insert(0.0);
+            *b = 0.0;
+            self.credit(b, joules * w);
+        }
+    }
+    // shared by POST /v1/take and the WebSocket channel; `joules` already validated. With
+    // `allow_partial` a short bucket is drained instead of denying, and `granted_j` says how much
+    fn take(&self, bucket: &str, joules: f64, allow_partial: bool) -> TakeResp {
+        let (r, granted_j) = self.take_from_bucket(bucket, joules, allow_partial);
+        self.log_takes([(bucket, joules, granted_j, r.remaining_j)], r.ok);
+        r
+    }
+    // GET /v1/takes entries: (bucket, requested_j, granted_j, remaining_j) per take, all stamped
+    // with the same time and power
+    fn log_takes<'a>(&self, takes: impl IntoIterator<Item = (&'a str, f64, f64, f64)>, ok: bool) {
+        let ts = Utc::now().timestamp_millis() as f64 / 1000.0;
+        let net_w = self.last.lock().compute_net_w;
+        let mut log = self.take_log.lock();
+        for (bucket, requested_j, granted_j, remaining_j) in takes {
+            log.record(takelog::Entry { ts, bucket: bucket.to_string(), requested_j, granted_j, ok, remaining_j, net_w });
+        }
+    }
+    // POST /v1/take_batch; amounts already validated. All-or-nothing under one lock, items naming
+    // the same bucket draw on it cumulatively. Ok holds every named bucket's balance afterwards
+    fn take_batch(&self, items: &[TakeReq]) -> Result<BTreeMap<String, f64>, ApiError> {
+        let mut buckets = self.buckets.lock();
+        let mut need: HashMap<&str, f64> = HashMap::new();
+        let mut short = None;
+        for (index, item) in items.iter().enumerate() {
+            let total = need.entry(&item.bucket).or_insert(0.0);
+            *total += item.joules;
+            let have = buckets.get(&item.bucket).copied().unwrap_or(0.0);
+            if have < *total {
+                short = Some(ShortItem { index, bucket: item.bucket.clone(), requested_j: item.joules, remaining_j: have });
+                break;
+            }
+        }
+        let total_j: f64 = need.values().sum();
+        if short.is_none() && total_j > 0.0 {
+            for (bucket, j) in need.iter().filter(|(_, j)| **j > 0.0) { *buckets.entry(bucket.to_string()).or_insert(0.0) -= j; }
+        }
+        let remaining_j: BTreeMap<String, f64> = items.iter()
+            .map(|i| (i.bucket.clone(), buckets.get(&i.bucket).copied().unwrap_or(0.0)))
+            .collect();
+        drop(buckets);
+        match short {
+            // nothing asked for (no items, or all 0 J): like a 0 J take, just report the balances
+            None if total_j == 0.0 => return Ok(remaining_j),
+            // one take of the batch total, matching cryoflux_take_total
+            None => {
+                self.take_granted.fetch_add(1, Ordering::Relaxed);
+                self.take_hist.lock().observe(total_j);
+            }
+            Some(_) => {
+                self.take_denied.fetch_add(1, Ordering::Relaxed);
+                tracing::info!(items = items.len(), "batch take denied");
+            }
+        }
+        // one /v1/takes entry per item, like a single take; remaining_j is after the whole batch
+        let granted = short.is_none();
+        self.log_takes(items.iter().map(|i| (&*i.bucket, i.joules, if granted { i.joules } else { 0.0 }, remaining_j[&i.bucket])), granted);
+        match short {
+            None => Ok(remaining_j),
+            Some(s) => Err(insufficient(&s.bucket, s.requested_j, s.remaining_j).with("index", s.index).with("remaining", remaining_j)),
+        }
+    }
+    // take with wait_ms: re-check each time joules are credited until the bucket covers the whole
+    // request, then take; at the deadline (or on shutdown) take whatever the usual rules allow
+    async fn take_waiting(&self, bucket: &str, joules: f64, allow_partial: bool, wait: Duration) -> TakeResp {
+        let deadline = tokio::time::Instant::now() + wait.min(MAX_TAKE_WAIT);
+        loop {
+            // registered before the balance check, so a credit in between isn't missed
+            let credited = self.credited.notified();
+            tokio::pin!(credited);
+            credited.as_mut().enable();
+            if self.buckets.lock().get(bucket).copied().unwrap_or(0.0) >= joules {
+                // all or nothing here; losing a race to another take just means waiting on
+                let r = self.take(bucket, joules, false);
+                if r.ok { return TakeResp { granted_j: allow_partial.then_some(joules), ..r }; }
+            }
+            tokio::select! {
+                _ = credited => {}
+                _ = tokio::time::sleep_until(deadline) => break,
+                _ = shutdown_requested(self.shutdown.clone()) => break,
+            }
+        }
+        self.take(bucket, joules, allow_partial)
+    }
+    fn take_from_bucket(&self, bucket: &str, joules: f64, allow_partial: bool) -> (TakeResp, f64) {
+        let mut buckets = self.buckets.lock();
+        if joules == 0.0 {
+            let remaining_j = buckets.get(bucket).copied().unwrap_or(0.0);
+            return (TakeResp { ok: true, remaining_j, granted_j: allow_partial.then_some(0.0) }, 0.0);
+        }
+        let b = buckets.entry(bucket.to_string()).or_insert(0.0);
+        let granted = if *b >= joules { joules } else if allow_partial && *b > 0.0 { *b } else { 0.0 };
+        if granted > 0.0 {
+            *b -= granted;
+            let remaining_j = *b;
+            drop(buckets);
+            self.take_granted.fetch_add(1, Ordering::Relaxed);
+            self.take_hist.lock().observe(granted);
+            (TakeResp { ok: true, remaining_j, granted_j: allow_partial.then_some(granted) }, granted)
+        } else {
+            self.take_denied.fetch_add(1, Ordering::Relaxed);
+            tracing::info!(bucket, requested_j = joules, remaining_j = *b, "take denied");
+            (TakeResp { ok: false, remaining_j: *b, granted_j: allow_partial.then_some(0.0) }, 0.0)
+        }
+    }
+    fn refund(&self, bucket: &str, joules: f64) -> RefundResp {
+        let mut buckets = self.buckets.lock();
+        let b = buckets.entry(bucket.to_string()).or_insert(0.0);
+        self.credit(b, joules);
+        RefundResp { ok: true, remaining_j: *b }
+    }
+    fn persisted(&self) -> persist::Persisted {
+        persist::Persisted {
+            buckets: self.buckets.lock().clone(),
+            bucket_j: None,
+            idle_gpu_w: *self.idle_gpu_w.lock(),
+            idle_cpu_w: *self.idle_cpu_w.lock(),
+            idle_wall_w: *self.idle_wall_w.lock(),
+            idle_dram_w: *self.idle_dram_w.lock(),
+            total_integrated_j: *self.total_integrated_j.lock(),
+            reservations: if self.cfg.shutdown_reservations == ShutdownReservations::Keep { self.held() } else { Vec::new() },
+        }
+    }
+    fn held(&self) -> Vec<persist::Held> {
+        let (now, now_unix) = (Instant::now(), Utc::now().timestamp_millis() as f64 / 1000.0);
+        self.reservations.lock().iter().map(|(&id, r)| persist::Held {
+            id,
+            bucket: r.bucket.clone(),
+            joules: r.joules,
+            expires_unix: now_unix + r.expires.saturating_duration_since(now).as_secs_f64(),
+        }).collect()
+    }
+    // JOULE_SHUTDOWN_RESERVATIONS=release: every hold goes back to its bucket before the final flush
+    fn release_all(&self) {
+        let mut buckets = self.buckets.lock();
+        let held: Vec<Reservation> = self.reservations.lock().drain().map(|(_, r)| r).collect();
+        if held.is_empty() { return; }
+        let (count, joules) = (held.len(), held.iter().map(|r| r.joules).sum::<f64>());
+        for r in held { self.credit(buckets.entry(r.bucket).or_insert(0.0), r.joules); }
+        tracing::info!(count, joules, "released held reservations on shutdown");
+    }
+    fn flush_state(&self) {
+        if let Some(path) = &self.cfg.state_file {
+            if let Err(e) = persist::save(path, &self.persisted()) {
+                tracing::warn!(path = %path.display(), error = %e, "failed to write state file");
+            }
+        }
+    }
 }
 
-#[derive(Deserialize)] struct TakeReq { joules: f64 }
-#[derive(Serialize)]   struct TakeResp { ok: bool, remaining_j: f64 }
+#[derive(Deserialize, utoipa::ToSchema)] struct TakeReq { joules: f64, #[serde(default = "default_bucket")] bucket: String, #[serde(default)] allow_partial: bool, wait_ms: Option<u64> }
+#[derive(Serialize, utoipa::ToSchema)]   struct TakeResp { ok: bool, remaining_j: f64, #[serde(skip_serializing_if = "Option::is_none")] granted_j: Option<f64> }
+#[derive(Deserialize, utoipa::ToSchema)] struct TakeBatchReq { items: Vec<TakeReq> }
+#[derive(Serialize, utoipa::ToSchema)]   struct TakeBatchResp { ok: bool, remaining_j: BTreeMap<String, f64> }
+struct ShortItem { index: usize, bucket: String, requested_j: f64, remaining_j: f64 }
+#[derive(Deserialize, utoipa::ToSchema)] struct AdmitReq { est_joules: f64, #[serde(default)] priority: u8, #[serde(default = "default_bucket")] bucket: String }
+#[derive(Deserialize, utoipa::ToSchema)] struct PowerCapReq { watts: f64, gpu: Option<u32> } // gpu: one metered NVML index, default all
+#[derive(Deserialize, utoipa::ToSchema)] struct RefundReq { joules: f64, #[serde(default = "default_bucket")] bucket: String }
+#[derive(Serialize, utoipa::ToSchema)]   struct RefundResp { ok: bool, remaining_j: f64 }
+#[derive(Deserialize)] struct RecentQuery { n: Option<usize>, api_version: Option<ApiVersion> }
+#[derive(Deserialize)] struct HistoryQuery { since: Option<f64>, api_version: Option<ApiVersion>, resolution: Option<rollup::Resolution> }
+#[derive(Deserialize)] struct ExportQuery { from: Option<f64>, to: Option<f64>, fields: Option<String>, #[serde(default)] resolution: rollup::Resolution, api_version: Option<ApiVersion> }
+#[derive(Deserialize)] struct SampleQuery { cores: Option<u8>, #[serde(default)] units: EnergyUnit, api_version: Option<ApiVersion> }
+#[derive(Deserialize)] struct BucketQuery { #[serde(default = "default_bucket")] bucket: String, #[serde(default)] units: EnergyUnit }
+#[derive(Serialize, utoipa::ToSchema)]   struct BucketResp { bucket: String, bucket_j: f64, cap_j: Option<f64>, ts: f64 }
+#[derive(Deserialize)] struct StreamQuery { api_version: Option<ApiVersion> }
+#[derive(Deserialize)] struct TopQuery { n: Option<usize> }
+#[derive(Deserialize)] struct TakesQuery { limit: Option<usize> }
+#[derive(Deserialize)] struct CanTakeQuery { joules: f64, #[serde(default = "default_bucket")] bucket: String }
+#[derive(Serialize, utoipa::ToSchema)]   struct CanTakeResp { ok: bool, remaining_j: f64, shortfall_j: f64 }
+#[derive(Deserialize)] struct CalibrateQuery { window_s: Option<f64> }
+#[derive(Serialize, utoipa::ToSchema)]   struct CalibrateResp { ok: bool, samples: usize, idle_gpu_w: f64, idle_cpu_w: f64, idle_wall_w: Option<f64>, idle_dram_w: Option<f64> }
+#[derive(Deserialize, utoipa::ToSchema)] struct ReserveReq { joules: f64, ttl_s: Option<f64>, #[serde(default = "default_bucket")] bucket: String }
+#[derive(Serialize, utoipa::ToSchema)]   struct ReserveResp { ok: bool, id: u64, remaining_j: f64, ttl_s: f64 }
+#[derive(Serialize, utoipa::ToSchema)]   struct SettleResp { ok: bool, joules: f64, remaining_j: f64 }
+
+// every startup setting, from the environment and config file; exits on a malformed value
+fn load_cfg(socket_tdp_w: Vec<f64>) -> Cfg {
+    Cfg {
+        net_j_per_byte: env_f("JOULE_NET_J_PER_BYTE", 0.0),
+        reservation_ttl_s: env_f("JOULE_RESERVATION_TTL_S", 300.0),
+        shutdown_reservations: match config::lookup("JOULE_SHUTDOWN_RESERVATIONS").as_deref() {
+            Some("release") | None => ShutdownReservations::Release,
+            // kept holds live in the state file; without one they would just vanish
+            Some("keep") if config::lookup("JOULE_STATE_FILE").is_none() => {
+                tracing::warn!("JOULE_SHUTDOWN_RESERVATIONS=keep needs JOULE_STATE_FILE, using release");
+                ShutdownReservations::Release
+            }
+            Some("keep") => ShutdownReservations::Keep,
+            Some(other) => {
+                tracing::warn!(value = other, "unknown JOULE_SHUTDOWN_RESERVATIONS, using release");
+                ShutdownReservations::Release
+            }
+        },
+        history_len: env_f("JOULE_HISTORY_LEN", 300.0) as usize,
+        history_minutes: env_f("JOULE_HISTORY_MINUTES", 1440.0) as usize,
+        history_hours: env_f("JOULE_HISTORY_HOURS", 168.0) as usize,
+        compression: env_b("JOULE_COMPRESSION"),
+        take_log_len: env_f("JOULE_TAKE_LOG_LEN", 1000.0) as usize,
+        stream_buffer: env_f("JOULE_STREAM_BUFFER", 16.0).max(1.0) as usize,
+        gpu_power_cap_w: env_opt_f("JOULE_GPU_POWER_CAP_W"),
+        bucket_cap_j: env_opt_f("JOULE_BUCKET_CAP_J"),
+        bucket_weights: parse_weights(&config::lookup("JOULE_BUCKET_WEIGHTS").unwrap_or_default()),
+        overflow: match config::lookup("JOULE_OVERFLOW_POLICY").as_deref() {
+            Some("spill") => Overflow::Spill,
+            Some("clamp") | None => Overflow::Clamp,
+            Some(other) => {
+                tracing::warn!(value = other, "unknown JOULE_OVERFLOW_POLICY, using clamp");
+                Overflow::Clamp
+            }
+        },
+        meter_mode: match config::lookup("JOULE_METER_MODE").as_deref() {
+            Some("gross") => MeterMode::Gross,
+            Some("net") | None => MeterMode::Net,
+            Some(other) => {
+                tracing::warn!(value = other, "unknown JOULE_METER_MODE, using net");
+                MeterMode::Net
+            }
+        },
+        power_source: match config::lookup("JOULE_POWER_SOURCE").as_deref() {
+            Some("battery") => PowerMode::Battery,
+            Some("auto") | None => PowerMode::Auto,
+            Some(other) => {
+                tracing::warn!(value = other, "unknown JOULE_POWER_SOURCE, using auto");
+                PowerMode::Auto
+            }
+        },
+        gpu_power_method: match config::lookup("JOULE_GPU_POWER_METHOD").as_deref() {
+            Some("instant") | None => GpuPowerMethod::Instant,
+            Some("energy_counter") => GpuPowerMethod::EnergyCounter,
+            Some("buffer") => GpuPowerMethod::Buffer,
+            Some(other) => {
+                tracing::warn!(value = other, "unknown JOULE_GPU_POWER_METHOD, using instant");
+                GpuPowerMethod::Instant
+            }
+        },
+        hash_algo: match config::lookup("JOULE_HASH_ALGO").as_deref() {
+            Some("blake3") | None => chain::HashAlgo::Blake3,
+            Some("sha256") => chain::HashAlgo::Sha256,
+            Some("none") => chain::HashAlgo::None,
+            Some(other) => {
+                tracing::warn!(value = other, "unknown JOULE_HASH_ALGO, using blake3");
+                chain::HashAlgo::Blake3
+            }
+        },
+        clock: match config::lookup("JOULE_CLOCK").as_deref() {
+            Some("mono") => ClockMode::Mono,
+            Some("both") => ClockMode::Both,
+            Some("wall") | None => ClockMode::Wall,
+            Some(other) => {
+                tracing::warn!(value = other, "unknown JOULE_CLOCK, using wall");
+                ClockMode::Wall
+            }
+        },
+        api_version: match config::lookup("JOULE_API_VERSION").as_deref() {
+            Some("v1") => ApiVersion::V1,
+            Some("v2") | None => ApiVersion::V2,
+            Some(other) => {
+                tracing::warn!(value = other, "unknown JOULE_API_VERSION, using v2");
+                ApiVersion::V2
+            }
+        },
+        state_file: config::lookup("JOULE_STATE_FILE").map(PathBuf::from),
+        state_flush_s: env_f("JOULE_STATE_FLUSH_S", 30.0),
+        csv_path: config::lookup("JOULE_CSV_PATH").map(PathBuf::from),
+        csv_flush_s: env_f("JOULE_CSV_FLUSH_S", 10.0),
+        csv_max_mb: env_opt_f("JOULE_CSV_MAX_MB"),
+        api_token: config::lookup("JOULE_API_TOKEN").filter(|t| !t.is_empty()).map(Arc::from),
+        protect_reads: env_b("JOULE_PROTECT_READS"),
+        cors_origins: config::lookup("JOULE_CORS_ORIGINS").map(|raw| parse_list(&raw)).filter(|o| !o.is_empty()),
+        bind_addr: or_exit(parse_bind_addr(&config::lookup("JOULE_BIND_ADDR").unwrap_or_else(|| "127.0.0.1".into()))),
+        port: or_exit(config::lookup("JOULE_PORT").map_or(Ok(8787), |p| {
+            p.trim().parse::<u16>().map_err(|e| format!("invalid JOULE_PORT {:?}: {}", p, e))
+        })),
+        bind_attempts: env_f("JOULE_BIND_ATTEMPTS", 10.0).max(1.0) as u32,
+        bind_retry_delay_s: env_f("JOULE_BIND_RETRY_DELAY_S", 1.0).max(0.0),
+        idle_gpu_w: env_f("JOULE_IDLE_GPU_W", 20.0),
+        idle_cpu_w: env_f("JOULE_IDLE_CPU_W", 15.0),
+        idle_gpu_bounds: or_exit(parse_idle_bounds("GPU")),
+        idle_cpu_bounds: or_exit(parse_idle_bounds("CPU")),
+        calibrate_window_s: env_f("JOULE_CALIBRATE_WINDOW_S", 30.0),
+        replay_file: config::lookup("JOULE_REPLAY_FILE").map(PathBuf::from),
+        replay_loop: env_b("JOULE_REPLAY_LOOP"),
+        socket_tdp_w,
+        subtract_self: env_b("JOULE_SUBTRACT_SELF"),
+        dram: env_b("JOULE_DRAM"),
+        docs: env_b("JOULE_DOCS"),
+        warmup_s: env_f("JOULE_WARMUP_S", 0.0).max(0.0),
+        active_w: env_f("JOULE_ACTIVE_W", 5.0),
+        duty_window_s: env_f("JOULE_DUTY_WINDOW_S", 300.0).max(1.0),
+        sampler_cpu: env_opt_f("JOULE_SAMPLER_CPU").filter(|c| *c >= 0.0 && c.fract() == 0.0).map(|c| c as usize),
+        sampler_priority: match config::lookup("JOULE_SAMPLER_PRIORITY").as_deref() {
+            Some("normal") | None => affinity::Priority::Normal,
+            Some("high") => affinity::Priority::High,
+            Some(other) => {
+                tracing::warn!(value = other, "unknown JOULE_SAMPLER_PRIORITY, using normal");
+                affinity::Priority::Normal
+            }
+        },
+        suspend_gap_s: env_f("JOULE_SUSPEND_GAP_S", 30.0),
+        take_hist_buckets: or_exit(takehist::parse_bounds(&config::lookup("JOULE_TAKE_HIST_BUCKETS").unwrap_or_else(|| "1,10,100,1000".into()))),
+        stats_windows: or_exit(stats::parse_windows(&config::lookup("JOULE_STATS_WINDOWS").unwrap_or_else(|| "1m,5m".into()))),
+        price_per_kwh: env_f("JOULE_PRICE_PER_KWH", 0.0),
+        co2_g_per_kwh: env_f("JOULE_CO2_G_PER_KWH", 0.0),
+        refill: or_exit(parse_refill()),
+        admit_priority_threshold: env_f("JOULE_ADMIT_PRIORITY_THRESHOLD", 5.0).clamp(0.0, 255.0) as u8,
+        admit_high_water_j: env_opt_f("JOULE_ADMIT_HIGH_WATER_J"),
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let cfg = Cfg {
-        cpu_tdp_w: env_f("JOULE_CPU_TDP_W", 65.0),
-        smoothing_alpha: env_f("JOULE_SMOOTHING", 0.2),
-        hz: env_f("JOULE_HZ", 1.0),
-        idle_learn_w: env_f("JOULE_IDLE_LEARN_W", 5.0),
-    };
-    let st = State {
-        cfg: cfg.clone(),
-        bucket_j: Arc::new(Mutex::new(0.0)),
-        idle_gpu_w: Arc::new(Mutex::new(20.0)),
-        idle_cpu_w: Arc::new(Mutex::new(15.0)),
-        last: Arc::new(Mutex::new(Last::default())),
-    };
+    let config_path = config::path_from_args();
+    let file_loaded = config::init(config_path.as_deref());
+    init_logging();
+    or_exit(file_loaded);
+    if let Some(path) = &config_path { tracing::info!(%path, "config file loaded"); }
+    let (tune, socket_tdp_w) = or_exit(load_tunables());
+    let cpu_curve = or_exit(load_cpu_curve());
+    let cfg = load_cfg(socket_tdp_w);
+    let restored = cfg.state_file.as_deref().and_then(persist::load);
+    let (host, machine_id) = identity();
+    tracing::info!(%host, %machine_id, "node identity");
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let st = State::new(cfg.clone(), tune, cpu_curve, restored.as_ref(), host.clone(), machine_id.clone(), shutdown_rx);
+    // seed the configured buckets, then restored balances (which may predate a lower cap)
+    {
+        let mut buckets = st.buckets.lock();
+        st.integrate(&mut buckets, 0.0);
+        if let Some(p) = restored {
+            for (name, j) in p.buckets.into_iter().chain(p.bucket_j.map(|j| (DEFAULT_BUCKET.to_string(), j))) {
+                st.credit(buckets.entry(name).or_insert(0.0), j);
+            }
+            // kept holds resume with what is left of their TTL; ones that lapsed while stopped
+            // are returned by the first expiry sweep
+            if !p.reservations.is_empty() {
+                let (now, now_unix) = (Instant::now(), Utc::now().timestamp_millis() as f64 / 1000.0);
+                let mut held = st.reservations.lock();
+                for r in p.reservations {
+                    let expires = now + Duration::from_secs_f64((r.expires_unix - now_unix).clamp(0.0, 1e9));
+                    held.insert(r.id, Reservation { bucket: r.bucket, joules: r.joules, expires });
+                }
+                st.next_reservation.store(held.keys().max().map_or(1, |m| m + 1), Ordering::Relaxed);
+                tracing::info!(count = held.len(), "restored held reservations");
+            }
+        }
+    }
+
+    tracing::info!(config = %config_json(&st), "starting JouleAgent");
 
     // Try NVML
-    let nvml = nvml_wrapper::Nvml::init().ok();
+    let nvml = Arc::new(Mutex::new(match nvml_wrapper::Nvml::init() {
+        Ok(n) => {
+            tracing::info!(devices = n.device_count().unwrap_or(0), "NVML initialized");
+            Some(n)
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "NVML unavailable, no NVIDIA GPU power");
+            None
+        }
+    }));
+    // Power sources: GPU ones add up, CPU ones in priority order (battery, measured, estimate)
+    let mut registry = sources::Registry::default();
+    let nvml_healthy = Arc::new(AtomicBool::new(false));
+    let mut gpu_vendors: Vec<&'static str> = Vec::new();
+    // only machines that had NVML at startup are watched for driver resets
+    let gpu_include = parse_list(&config::lookup("JOULE_GPU_INCLUDE").unwrap_or_default());
+    let gpu_exclude = parse_list(&config::lookup("JOULE_GPU_EXCLUDE").unwrap_or_default());
+    let gpu_devices = match nvml.lock().as_ref() {
+        Some(n) => {
+            let devices = or_exit(select_gpus(n, &gpu_include, &gpu_exclude));
+            for &i in &devices {
+                let dev = n.device_by_index(i);
+                let name = dev.as_ref().ok().and_then(|d| d.name().ok()).unwrap_or_default();
+                let uuid = dev.as_ref().ok().and_then(|d| d.uuid().ok()).unwrap_or_default();
+                tracing::info!(index = i, %name, %uuid, "metering GPU");
+            }
+            devices
+        }
+        None => {
+            if !gpu_include.is_empty() || !gpu_exclude.is_empty() { tracing::warn!("JOULE_GPU_INCLUDE/JOULE_GPU_EXCLUDE set but NVML is unavailable; ignoring"); }
+            Vec::new()
+        }
+    };
+    if nvml.lock().is_some() { registry.add_gpu(Box::new(sources::NvmlSource::new(nvml.clone(), nvml_healthy.clone(), gpu_devices.clone(), cfg.gpu_power_method))); }
+    if !gpu_devices.is_empty() { gpu_vendors.push("nvidia"); }
+    if let Some(watts) = cfg.gpu_power_cap_w {
+        match nvml.lock().as_ref().map(|n| powercap::set(n, &gpu_devices, watts)) {
+            None => tracing::warn!("JOULE_GPU_POWER_CAP_W set but NVML is unavailable; ignoring"),
+            Some(Ok(_)) => tracing::info!(watts, devices = ?gpu_devices, "GPU power cap applied"),
+            Some(Err(powercap::CapErr::OutOfRange(e))) => or_exit(Err(format!("JOULE_GPU_POWER_CAP_W: {}", e))),
+            Some(Err(e)) => tracing::warn!(error = %e, "cannot apply JOULE_GPU_POWER_CAP_W"),
+        }
+    }
+    if let Some(amdgpu) = sources::Amdgpu::probe() {
+        tracing::info!(devices = amdgpu.devices(), "amdgpu power sensors found");
+        gpu_vendors.push("amd");
+        registry.add_gpu(Box::new(amdgpu));
+    }
+    // Try powermetrics on macOS (Apple Silicon CPU + GPU package power)
+    #[cfg(target_os = "macos")]
+    let apple = powermetrics::spawn((1000.0 / tune.hz) as u64, st.shutdown.clone());
+    #[cfg(target_os = "macos")]
+    registry.add_gpu(Box::new(sources::Powermetrics::gpu(apple.clone())));
+    registry.add_cpu(Box::new(sources::BatterySource::new(cfg.power_source)));
+    // Try RAPL (falls back to the estimate when missing or unreadable)
+    if let Some(rapl) = sources::Rapl::probe() { registry.add_cpu(Box::new(rapl)); }
+    #[cfg(target_os = "macos")]
+    registry.add_cpu(Box::new(sources::Powermetrics::cpu(apple)));
+    // curve or TDP; on Windows PDH scales it by the current clock
+    registry.add_cpu(Box::new(sources::Estimate::new(st.cpu_curve.clone(), &cfg.socket_tdp_w)));
+    tracing::info!(sources = ?registry.names(), "power sources registered");
+    let mut dram = if cfg.dram { sources::Dram::probe() } else { None };
+    match &dram {
+        Some(d) => tracing::info!(domains = d.domains(), "RAPL DRAM power found"),
+        None if cfg.dram => tracing::warn!("JOULE_DRAM set but no readable RAPL dram domain; no dram_w"),
+        None => {}
+    }
+
+    // A recorded trace replaces the hardware readings entirely
+    let mut replay = cfg.replay_file.as_deref().map(|p| replay::Replay::new(or_exit(replay::load(p)), cfg.replay_loop));
+    if let Some(path) = &cfg.replay_file { tracing::info!(path = %path.display(), looping = cfg.replay_loop, "replaying power trace"); }
 
     // Sampler loop
     let st_loop = st.clone();
-    tokio::spawn(async move {
+    let nvml_loop = nvml.clone();
+    let metered_gpus = gpu_devices.clone(); // the sampler takes gpu_devices; /v1/powercap needs them too
+    let sampler = async move {
         let mut sys = System::new();
-        let period = Duration::from_secs_f64(1.0 / st_loop.cfg.hz.max(0.1));
+        // warm-up refresh: cpu usage is only meaningful relative to a previous refresh
+        sys.refresh_cpu();
+        let mut last_cpu_refresh = Instant::now();
+        let mut cpu_ready = false;
+        let self_pid = sysinfo::get_current_pid().ok();
+        let mut networks = Networks::new_with_refreshed_list();
+        let mut idle_sockets: Vec<f64> = Vec::new();
+        let mut gpu_clamp = ClampWatch::new("gpu", st_loop.cfg.idle_gpu_bounds);
+        let mut cpu_clamp = ClampWatch::new("cpu", st_loop.cfg.idle_cpu_bounds);
+        let mut cgroup = cgroup::Cgroup::probe();
+        let mut cpu_throttle = throttle::CpuThrottle::probe();
+        if let Some(c) = &cgroup { tracing::info!(quota_cpus = c.quota_cpus(), "cgroup CPU limit found, estimating from cgroup usage"); }
+        let mut prev_tick: Option<Instant> = None;
+        let mut prev_power_w: Option<f64> = None; // integrated power of the previous tick
+        let mut prev_wall: Option<chrono::DateTime<Utc>> = None;
+        let mut suspend_gaps: u64 = 0;
+        let mut last_publish: Option<Instant> = None;
+        let mut rolling = stats::Rolling::new(&st_loop.cfg.stats_windows);
+        let mut duty = duty::Duty::new(st_loop.cfg.active_w, st_loop.cfg.duty_window_s);
+        let (mut cost_usd, mut co2_g) = (0.0, 0.0);
+        let mut warm_ticks: u64 = 0; // readings learned from during JOULE_WARMUP_S
+        let mut power_ema: [Vec<f64>; 3] = Default::default(); // [gpu_w, cpu_w], per_gpu_w, per_socket_w
+        if st_loop.cfg.warmup_s > 0.0 { tracing::info!(warmup_s = st_loop.cfg.warmup_s, "warming up: learning idle baselines, takes refused"); }
         loop {
             let loop_start = Instant::now();
-            sys.refresh_cpu();
-            let cpu_usage = avg_cpu_usage(&sys); // 0..100
-            let cpu_w = (cpu_usage as f64 / 100.0) * st_loop.cfg.cpu_tdp_w;
-            let gpu_w = gpu_power_w(&nvml);
+            // snapshot the tunables so a concurrent PUT /v1/config applies from the next tick
+            let tune = *st_loop.tune.lock();
+            let period = Duration::from_secs_f64(1.0 / tune.hz);
+            // real time since the previous tick: a stalled or descheduled loop must not lose joules
+            let (interval, dt) = tick_dt(&mut prev_tick, loop_start, period);
+            // the monotonic clock stops during system sleep on Linux and macOS, the wall clock doesn't:
+            // a wall-clock jump far beyond the period means we were suspended, and whatever the gap
+            // would integrate to (nominal or measured dt) is not energy we actually saw
+            let wall_now = Utc::now();
+            let wall_gap_s = prev_wall.map_or(0.0, |p| (wall_now - p).num_milliseconds() as f64 / 1000.0);
+            prev_wall = Some(wall_now);
+            let resumed = wall_gap_s > st_loop.cfg.suspend_gap_s.max(2.0 * period.as_secs_f64());
+            if resumed {
+                suspend_gaps += 1;
+                tracing::info!(gap_s = wall_gap_s, "resume detected, not integrating the gap");
+            }
+            let replayed = replay.as_mut().map(|r| r.step());
+            let dt = replayed.as_ref().and_then(|r| r.dt).unwrap_or(dt);
+            let (gpu_w, per_gpu_w, per_gpu_stats, nvml_healthy, battery, cpu_w, per_socket_w, cpu_source, warmup, power_sources, quality, self_share, source_latency, cgroup_aware) = match &replayed {
+                // recorded readings stand in for every hardware read; everything downstream runs unchanged
+                Some(r) => (r.gpu_w, vec![r.gpu_w], Vec::new(), false, None, r.cpu_w, vec![r.cpu_w], "replay", false, vec!["replay"], sources::Quality::Measured, 0.0, Vec::new(), false),
+                None => {
+                    let battery = battery_power();
+                    // refreshes closer than MINIMUM_CPU_UPDATE_INTERVAL are meaningless; reuse the last one
+                    if loop_start - last_cpu_refresh >= sysinfo::MINIMUM_CPU_UPDATE_INTERVAL {
+                        sys.refresh_cpu();
+                        if let Some(pid) = self_pid { sys.refresh_process_specifics(pid, ProcessRefreshKind::new().with_cpu()); }
+                        last_cpu_refresh = loop_start;
+                        cpu_ready = true;
+                    }
+                    let cpu_usage = avg_cpu_usage(&sys);
+                    // the agent's share of the CPU time in use: process usage is per core, so scale it
+                    // down to the machine-wide 0..100 that cpu_usage is on
+                    let self_share = match self_pid.and_then(|pid| sys.process(pid)) {
+                        Some(p) if cpu_usage > 0.0 => (p.cpu_usage() as f64 / sys.cpus().len().max(1) as f64 / cpu_usage as f64).min(1.0),
+                        _ => 0.0,
+                    };
+                    // in a CPU-limited container the estimate runs on the cgroup's usage of its quota,
+                    // against the quota's share of the host TDP
+                    let limited = cgroup.as_mut().and_then(|c| Some((c.usage_pct()?, c.quota_cpus())));
+                    let cgroup_aware = limited.is_some();
+                    let (est_usage, est_tdp_w) = match limited {
+                        Some((usage, quota)) => (usage, tune.cpu_tdp_w * (quota / sys.cpus().len().max(1) as f64).min(1.0)),
+                        None => (cpu_usage, tune.cpu_tdp_w),
+                    };
+                    let tick = sources::Tick { dt, cpu_usage: est_usage, cpu_ready, cpu_tdp_w: est_tdp_w, battery, gpu_w: 0.0 };
+                    let r = registry.sample(&tick);
+                    let per_gpu_stats = gpu_stats(&nvml_loop.lock(), &gpu_devices);
+                    let nvml_healthy = nvml_healthy.load(Ordering::Relaxed);
+                    (r.gpu_w, r.per_gpu_w, per_gpu_stats, nvml_healthy, battery, r.cpu_w, r.per_socket_w, r.cpu_source, r.warmup, r.active, r.quality, self_share, r.latency, cgroup_aware)
+                }
+            };
+            // JOULE_POWER_SMOOTHING: an EMA over the readings before idle learning, integration and
+            // publishing see them; a resume starts it over rather than blending in pre-suspend power
+            let (gpu_w_raw, cpu_w_raw) = (gpu_w, cpu_w);
+            if resumed || tune.power_smoothing >= 1.0 { power_ema.iter_mut().for_each(Vec::clear); }
+            let (gpu_w, cpu_w, per_gpu_w, per_socket_w) = if tune.power_smoothing < 1.0 {
+                let [totals, gpus, sockets] = &mut power_ema;
+                let t = smooth(totals, &[gpu_w, cpu_w], tune.power_smoothing);
+                (t[0], t[1], smooth(gpus, &per_gpu_w, tune.power_smoothing), smooth(sockets, &per_socket_w, tune.power_smoothing))
+            } else {
+                (gpu_w, cpu_w, per_gpu_w, per_socket_w)
+            };
+            let per_core_usage: Vec<f32> = sys.cpus().iter().map(|c| c.cpu_usage()).collect();
+            let per_core_w = split_by_usage(cpu_w, &per_core_usage);
+            let network_w = if replayed.is_some() { 0.0 } else {
+                networks.refresh();
+                let net_bytes: u64 = networks.list().values().map(|d| d.received() + d.transmitted()).sum();
+                net_bytes as f64 * st_loop.cfg.net_j_per_byte / dt
+            };
+
+            let wall_w = *st_loop.wall_w.lock();
+            let dram_w = if replayed.is_some() { None } else { dram.as_mut().and_then(|d| d.watts(dt)) };
+            let paused = st_loop.paused.load(Ordering::Relaxed);
+            let warmup_left_s = st_loop.warmup_left_s();
+            let warming_up = warmup_left_s > 0.0;
+            if !warming_up && warm_ticks > 0 {
+                tracing::info!(idle_gpu_w = *st_loop.idle_gpu_w.lock(), idle_cpu_w = *st_loop.idle_cpu_w.lock(), readings = warm_ticks, "warm-up done, idle learning gated again");
+                warm_ticks = 0;
+            }
 
             // read current idles and update EMA baseline in a tight scope so guards are dropped
-            {
+            let (cpu_net_w, dram_net_w) = {
                 let mut idle_g = st_loop.idle_gpu_w.lock();
                 let mut idle_c = st_loop.idle_cpu_w.lock();
-                let net_w_raw = (gpu_w - *idle_g).max(0.0) + (cpu_w - *idle_c).max(0.0);
-                // update EMA baseline **only** when net power ~ idle
-                if net_w_raw < st_loop.cfg.idle_learn_w {
-                    *idle_g = st_loop.cfg.smoothing_alpha * gpu_w + (1.0 - st_loop.cfg.smoothing_alpha) * *idle_g;
-                    *idle_c = st_loop.cfg.smoothing_alpha * cpu_w + (1.0 - st_loop.cfg.smoothing_alpha) * *idle_c;
+                rebase_sockets(&mut idle_sockets, *idle_c, per_socket_w.len());
+                // per socket, so an idle socket can't hide a busy one below the combined baseline
+                let socket_net = |idle: &[f64]| per_socket_w.iter().zip(idle).map(|(w, i)| (w - i).max(0.0)).sum::<f64>();
+                let net_w_raw = (gpu_w - *idle_g).max(0.0) + socket_net(&idle_sockets);
+                // update EMA baseline **only** when net power ~ idle. During warm-up the machine is
+                // taken to be idle: every reading counts, as a running mean so the guesses go at once
+                let learn = !warmup && !paused && (warming_up || tune.idle_gate(net_w_raw, *idle_g + *idle_c));
+                if learn && warming_up { warm_ticks += 1; }
+                let alpha = if warming_up { (1.0 / warm_ticks.max(1) as f64).max(tune.smoothing_alpha) } else { tune.smoothing_alpha };
+                if learn {
+                    *idle_g = gpu_clamp.apply(alpha * gpu_w + (1.0 - alpha) * *idle_g);
+                    for (idle, w) in idle_sockets.iter_mut().zip(&per_socket_w) {
+                        *idle = alpha * w + (1.0 - alpha) * *idle;
+                    }
+                    // the bound is on the combined CPU baseline; the sockets keep their proportions
+                    *idle_c = cpu_clamp.apply(idle_sockets.iter().sum());
+                    rebase_sockets(&mut idle_sockets, *idle_c, per_socket_w.len());
                 }
-            }
+                // the wall baseline learns the same way, on its own reading
+                if let Some(w) = wall_w {
+                    let mut idle_w = st_loop.idle_wall_w.lock();
+                    let idle_w = idle_w.get_or_insert(w);
+                    if !warmup && !paused && (warming_up || tune.idle_gate((w - *idle_w).max(0.0), *idle_w)) {
+                        *idle_w = alpha * w + (1.0 - alpha) * *idle_w;
+                    }
+                }
+                // DRAM moves little with load, so it learns whenever CPU and GPU look idle
+                let dram_net_w = dram_w.map(|w| {
+                    let mut idle_d = st_loop.idle_dram_w.lock();
+                    let idle_d = idle_d.get_or_insert(w);
+                    if learn { *idle_d = alpha * w + (1.0 - alpha) * *idle_d; }
+                    (w - *idle_d).max(0.0)
+                });
+                (socket_net(&idle_sockets), dram_net_w)
+            };
 
             let idle_g_now = *st_loop.idle_gpu_w.lock();
             let idle_c_now = *st_loop.idle_cpu_w.lock();
-            let net_w = (gpu_w - idle_g_now).max(0.0) + (cpu_w - idle_c_now).max(0.0);
+            let idle_wall_now = *st_loop.idle_wall_w.lock();
+            // the agent's own cost: its share of the CPU power above idle, attributed like /v1/top does
+            let agent_self_w = (cpu_w - idle_c_now).max(0.0) * self_share;
+            // the plug measures everything, so when it is live it is authoritative
+            let compute_net_w = match (wall_w, idle_wall_now) {
+                (Some(w), Some(idle)) => (w - idle).max(0.0),
+                _ => (gpu_w - idle_g_now).max(0.0) + cpu_net_w + dram_net_w.unwrap_or(0.0),
+            };
+            let compute_net_w = if st_loop.cfg.subtract_self { (compute_net_w - agent_self_w).max(0.0) } else { compute_net_w };
+            // what the bucket integrates: the above-idle power, or everything drawn in gross mode
+            let metered_w = match st_loop.cfg.meter_mode {
+                MeterMode::Net => compute_net_w,
+                MeterMode::Gross => {
+                    let gross_w = wall_w.unwrap_or(gpu_w + cpu_w + dram_w.unwrap_or(0.0));
+                    if st_loop.cfg.subtract_self { (gross_w - agent_self_w).max(0.0) } else { gross_w }
+                }
+            };
 
-            // integrate Joules (use sampling period, not loop elapsed time)
-            let dt = period.as_secs_f64();
-            {
-                let mut b = st_loop.bucket_j.lock();
-                *b += net_w * dt;
-            }
+            // integrate Joules over the measured tick interval, snapshotting the buckets under the same
+            // lock so a concurrent take can't land between the credit and the published/hashed values
+            let co2_g_per_kwh = *st_loop.co2_g_per_kwh.lock();
+            let mut tick_joules = 0.0;
+            let buckets: BTreeMap<String, f64> = {
+                let mut b = st_loop.buckets.lock();
+                // nothing is credited against a baseline still being learned
+                if !warmup && !resumed && !paused && !warming_up {
+                    let joules = step_joules(prev_power_w, metered_w + network_w, dt);
+                    tick_joules = joules;
+                    st_loop.integrate(&mut b, joules);
+                    *st_loop.total_integrated_j.lock() += joules;
+                    // priced per step so a changing carbon intensity applies only to energy used after it
+                    cost_usd += joules / J_PER_KWH * st_loop.cfg.price_per_kwh;
+                    co2_g += joules / J_PER_KWH * co2_g_per_kwh;
+                }
+                b.iter().map(|(k, v)| (k.clone(), *v)).collect()
+            };
+            prev_power_w = Some(metered_w + network_w);
+            // a suspend gap or a warming-up source says nothing about how busy the machine was
+            if !warmup && !resumed { duty.push(st_loop.started.elapsed().as_secs_f64(), dt, compute_net_w); }
+            if !warmup { st_loop.extremes.lock().push(wall_now.timestamp_millis() as f64 / 1000.0, gpu_w, cpu_w, compute_net_w); }
+            st_loop.rollups.lock().push(wall_now.timestamp_millis() as f64 / 1000.0, compute_net_w, tick_joules);
 
-            // publish last sample
-            {
+            // publish last sample at the publish rate; the integration above ran regardless. Half a
+            // tick of slack keeps jitter from skipping a publish
+            let publish_period = Duration::from_secs_f64(1.0 / tune.publish_hz.unwrap_or(tune.hz));
+            let publish_due = last_publish.is_none_or(|t| loop_start - t + period / 2 >= publish_period);
+            if publish_due {
+                let actual_dt_s = last_publish.replace(loop_start).map(|t| (loop_start - t).as_secs_f64());
                 let mut s = st_loop.last.lock();
-                s.ts = Utc::now().timestamp_millis() as f64 / 1000.0;
-                s.gpu_w = gpu_w; s.cpu_w = cpu_w;
+                s.period_s = publish_period.as_secs_f64();
+                s.actual_dt_s = actual_dt_s;
+                s.wall_ts = Utc::now().timestamp_millis() as f64 / 1000.0;
+                // millisecond resolution like the wall clock, so both hash the same way
+                let mono_ts = (st_loop.started.elapsed().as_millis() as f64) / 1000.0;
+                (s.ts, s.mono_ts) = match st_loop.cfg.clock {
+                    ClockMode::Wall => (s.wall_ts, None),
+                    ClockMode::Mono => (mono_ts, None),
+                    ClockMode::Both => (s.wall_ts, Some(mono_ts)),
+                };
+                s.gpu_w = gpu_w; s.per_gpu_w = per_gpu_w; s.cpu_w = cpu_w; s.per_socket_w = per_socket_w;
+                s.gpu_w_raw = gpu_w_raw; s.cpu_w_raw = cpu_w_raw;
+                s.gpu_vendors.clone_from(&gpu_vendors);
+                s.gpu_devices.clone_from(&gpu_devices);
+                s.nvml_healthy = nvml_healthy;
+                s.throttle_reasons = per_gpu_stats.iter()
+                    .flat_map(|g| g.throttle_reasons.iter().map(move |r| format!("gpu{}:{}", g.index, r)))
+                    .collect();
+                // polled per publish so no counter increment between samples goes unseen
+                if replayed.is_none() && cpu_throttle.as_mut().is_some_and(|t| t.poll()) { s.throttle_reasons.push("cpu:thermal".into()); }
+                s.per_gpu_stats = per_gpu_stats;
+                s.per_core_usage = per_core_usage; s.per_core_w = per_core_w;
                 s.idle_gpu_w = idle_g_now; s.idle_cpu_w = idle_c_now;
-                s.net_w = net_w; s.bucket_j = *st_loop.bucket_j.lock();
+                s.wall_w = wall_w;
+                s.idle_wall_w = wall_w.and(idle_wall_now);
+                s.dram_w = dram_w;
+                s.idle_dram_w = dram_w.and(*st_loop.idle_dram_w.lock());
+                s.model_error_w = wall_w.map(|w| w - (gpu_w + cpu_w));
+                s.compute_net_w = compute_net_w; s.network_w = network_w;
+                s.metered_w = metered_w; s.meter_mode = st_loop.cfg.meter_mode;
+                s.agent_self_w = agent_self_w;
+                s.buckets = buckets;
+                s.bucket_j = s.buckets.values().sum();
+                s.spilled_j = *st_loop.spilled_j.lock();
+                s.total_integrated_j = *st_loop.total_integrated_j.lock();
+                s.cpu_source = cpu_source;
+                s.cgroup_aware = cgroup_aware;
+                s.power_sources = power_sources;
+                // a live plug measures the whole machine, whatever the model behind it did
+                s.power_quality = if wall_w.is_some() { sources::Quality::Measured } else { quality };
+                s.warmup = warmup;
+                s.warming_up = warming_up;
+                s.active_duty_pct = duty.pct();
+                s.active_s = duty.active_s();
+                s.idle_s = duty.idle_s();
+                s.extremes = *st_loop.extremes.lock();
+                s.warmup_remaining_s = warmup_left_s;
+                s.paused = paused;
+                s.suspend_gaps = suspend_gaps;
+                s.battery_w = battery.map(|b| b.watts);
+                s.on_ac = battery.map(|b| b.on_ac);
+                s.refill_in_s = st_loop.next_refill.lock().map(|t| t.saturating_duration_since(Instant::now()).as_secs_f64());
+                s.cost_usd = cost_usd;
+                s.co2_g = co2_g;
+                s.cost_rate_usd_per_h = (metered_w + network_w) / 1000.0 * st_loop.cfg.price_per_kwh;
+                s.co2_g_per_kwh = co2_g_per_kwh;
+                rolling.push(s.ts, compute_net_w);
+                s.stats = rolling.snapshot();
+                // chain onto the previous sample so dropped or reordered readings are detectable
+                let algo = st_loop.cfg.hash_algo;
+                if algo != chain::HashAlgo::None {
+                    s.prev_hash = if s.hash.is_empty() { chain::GENESIS_HASH.to_string() } else { std::mem::take(&mut s.hash) };
+                    s.hash = algo.chain(&s.prev_hash, s.ts, s.compute_net_w, s.bucket_j);
+                }
+                s.hash_algo = algo.name();
+                s.sig = None;
+                if let Some(key) = &st_loop.signer { s.sig = Some(sign::sign_json(key, &sample_json(&s))); }
+                {
+                    let mut h = st_loop.history.lock();
+                    if h.len() >= st_loop.cfg.history_len { h.pop_front(); }
+                    if st_loop.cfg.history_len > 0 {
+                        h.push_back(s.clone());
+                        st_loop.history_seq.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                // no subscribers is not an error
+                let _ = st_loop.samples_tx.send(s.clone());
+                *st_loop.last_tick.lock() = Instant::now();
             }
 
+            tracing::debug!(gpu_w, cpu_w, cpu_source, compute_net_w, network_w, dt, warmup, "sample");
+
             // cadence
             let slip = loop_start.elapsed();
             let wait = if period > slip { period - slip } else { Duration::from_millis(0) };
-            tokio::time::sleep(wait).await;
+            st_loop.diag.lock().record(interval, period, slip, wait, source_latency);
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = shutdown_requested(st_loop.shutdown.clone()) => break,
+            }
+        }
+    };
+    let diag = st.diag.clone();
+    let sampler = affinity::spawn(sampler, st.cfg.sampler_cpu, st.cfg.sampler_priority, move |p| diag.lock().placed(p));
+
+    // Background sinks fed from the sample broadcast; awaited on shutdown so they can flush
+    let mut sinks = Vec::new();
+    if let Some(path) = st.cfg.csv_path.clone() {
+        match csvlog::CsvLog::open(&path, st.cfg.csv_max_mb) {
+            Ok(log) => sinks.push(tokio::spawn(csv_sink(st.clone(), log))),
+            Err(e) => tracing::warn!(path = %path.display(), error = %e, "CSV log disabled"),
+        }
+    }
+
+    if let (Some(url), Some(token)) = (config::lookup("JOULE_INFLUX_URL"), config::lookup("JOULE_INFLUX_TOKEN")) {
+        let cfg = influx::InfluxCfg {
+            url,
+            token,
+            org: config::lookup("JOULE_INFLUX_ORG").unwrap_or_default(),
+            bucket: config::lookup("JOULE_INFLUX_BUCKET").unwrap_or_else(|| "cryoflux".into()),
+            flush_s: env_f("JOULE_INFLUX_FLUSH_S", 10.0),
+            max_retries: env_f("JOULE_INFLUX_MAX_RETRIES", 5.0) as u32,
+        };
+        tracing::info!(url = %cfg.url, bucket = %cfg.bucket, "pushing samples to InfluxDB");
+        sinks.push(tokio::spawn(influx::run(st.clone(), cfg)));
+    }
+
+    if let Some(url) = config::lookup("JOULE_PROM_REMOTE_WRITE_URL") {
+        let token = config::lookup("JOULE_PROM_REMOTE_WRITE_TOKEN");
+        let user = config::lookup("JOULE_PROM_REMOTE_WRITE_USER");
+        if token.is_some() && user.is_some() { tracing::warn!("both JOULE_PROM_REMOTE_WRITE_TOKEN and _USER set, using the bearer token"); }
+        let auth = match (token, user) {
+            (Some(token), _) => remote_write::Auth::Bearer(token),
+            (None, Some(user)) => remote_write::Auth::Basic(user, config::lookup("JOULE_PROM_REMOTE_WRITE_PASSWORD")),
+            (None, None) => remote_write::Auth::None,
+        };
+        let cfg = remote_write::RemoteWriteCfg {
+            url,
+            auth,
+            interval_s: env_f("JOULE_PROM_REMOTE_WRITE_INTERVAL_S", 15.0),
+            max_retries: env_f("JOULE_PROM_REMOTE_WRITE_MAX_RETRIES", 5.0) as u32,
+        };
+        tracing::info!(url = %cfg.url, interval_s = cfg.interval_s, "pushing metrics over Prometheus remote write");
+        sinks.push(tokio::spawn(remote_write::run(st.clone(), cfg)));
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(port) = env_opt_f("JOULE_GRPC_PORT") {
+        let addr = SocketAddr::new(st.cfg.bind_addr, port as u16);
+        tracing::info!(%addr, "gRPC listening");
+        sinks.push(tokio::spawn(grpc::serve(st.clone(), addr)));
+    }
+    #[cfg(not(feature = "grpc"))]
+    if config::lookup("JOULE_GRPC_PORT").is_some() {
+        tracing::warn!("JOULE_GRPC_PORT set but this build lacks the \"grpc\" feature; ignoring");
+    }
+
+    if let Some(url) = config::lookup("JOULE_WEBHOOK_URL") {
+        let cfg = alert::AlertCfg {
+            url,
+            low_j: env_opt_f("JOULE_ALERT_LOW_J"),
+            high_j: env_opt_f("JOULE_ALERT_HIGH_J"),
+            hysteresis_j: env_opt_f("JOULE_ALERT_HYSTERESIS_J").map(|h| h.max(0.0)),
+            spike_w: env_opt_f("JOULE_ALERT_SPIKE_W"),
+            spike_dwell_s: env_f("JOULE_ALERT_SPIKE_DWELL_S", 10.0),
+            min_interval_s: env_f("JOULE_ALERT_MIN_INTERVAL_S", 60.0),
+            max_retries: env_f("JOULE_WEBHOOK_MAX_RETRIES", 3.0) as u32,
+        };
+        if cfg.low_j.is_none() && cfg.high_j.is_none() && cfg.spike_w.is_none() {
+            tracing::warn!("JOULE_WEBHOOK_URL set without JOULE_ALERT_LOW_J, JOULE_ALERT_HIGH_J or JOULE_ALERT_SPIKE_W; no alerts will fire");
         }
+        tracing::info!(low_j = ?cfg.low_j, high_j = ?cfg.high_j, spike_w = ?cfg.spike_w, "posting alerts to webhook");
+        sinks.push(tokio::spawn(alert::run(st.clone(), cfg)));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = config_path.clone() {
+        tokio::spawn(reload::run(st.clone(), path));
+    }
+
+    if let Some(url) = config::lookup("JOULE_WALLPLUG_URL") {
+        let cfg = wallplug::WallplugCfg {
+            url,
+            field: config::lookup("JOULE_WALLPLUG_FIELD"),
+            interval_s: env_f("JOULE_WALLPLUG_INTERVAL_S", 5.0),
+        };
+        tracing::info!(url = %cfg.url, "wall power from smart plug");
+        tokio::spawn(wallplug::run(st.clone(), cfg));
+    }
+
+    if let Some(url) = config::lookup("JOULE_CO2_API_URL") {
+        let cfg = carbon::CarbonCfg {
+            url,
+            pointer: config::lookup("JOULE_CO2_API_FIELD").unwrap_or_else(|| "/carbonIntensity".into()),
+            token: config::lookup("JOULE_CO2_API_TOKEN"),
+            refresh_s: env_f("JOULE_CO2_API_REFRESH_S", 900.0),
+        };
+        tracing::info!(url = %cfg.url, "carbon intensity from API");
+        tokio::spawn(carbon::run(st.clone(), cfg));
+    }
+
+    #[cfg(feature = "mqtt")]
+    if let (Some(url), Some(topic)) = (config::lookup("JOULE_MQTT_URL"), config::lookup("JOULE_MQTT_TOPIC")) {
+        let cfg = mqtt::MqttCfg {
+            url,
+            topic,
+            username: config::lookup("JOULE_MQTT_USERNAME"),
+            password: config::lookup("JOULE_MQTT_PASSWORD"),
+            interval_s: env_opt_f("JOULE_MQTT_INTERVAL_S"),
+        };
+        let opts = or_exit(mqtt::options(&cfg));
+        tracing::info!(broker = %opts.broker_address().0, topic = %cfg.topic, "publishing samples over MQTT");
+        sinks.push(tokio::spawn(mqtt::run(st.clone(), cfg, opts)));
+    }
+
+    #[cfg(feature = "otel")]
+    let otel_provider = config::lookup("JOULE_OTEL_ENDPOINT").map(|endpoint| {
+        let cfg = otel::OtelCfg {
+            endpoint,
+            service_name: config::lookup("JOULE_OTEL_SERVICE_NAME").unwrap_or_else(|| "cryoflux".into()),
+            interval_s: env_f("JOULE_OTEL_INTERVAL_S", 10.0),
+        };
+        tracing::info!(endpoint = %cfg.endpoint, service = %cfg.service_name, "exporting metrics over OTLP");
+        or_exit(otel::start(&st, &cfg))
     });
 
+    // Periodic state flush
+    if st.cfg.state_file.is_some() {
+        let st_flush = st.clone();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(Duration::from_secs_f64(st_flush.cfg.state_flush_s.max(1.0)));
+            tick.tick().await; // first tick fires immediately
+            loop {
+                tick.tick().await;
+                st_flush.flush_state();
+            }
+        });
+    }
+
+    // Scheduled refill (quota mode)
+    if let Some(refill) = st.cfg.refill {
+        let st_refill = st.clone();
+        tokio::spawn(async move {
+            let period = Duration::from_secs_f64(refill.interval_s);
+            let mut tick = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                tick.tick().await;
+                {
+                    let mut b = st_refill.buckets.lock();
+                    match refill.mode {
+                        RefillMode::Add => st_refill.integrate(&mut b, refill.joules),
+                        RefillMode::Set => st_refill.set_total(&mut b, refill.joules),
+                    }
+                }
+                *st_refill.next_refill.lock() = Some(Instant::now() + period);
+                tracing::info!(joules = refill.joules, set = refill.mode == RefillMode::Set, "buckets refilled");
+            }
+        });
+    }
+
+    // Forget rate-limit state of clients that went quiet so the table can't grow without bound
+    if let Some(limiter) = st.take_limiter.clone() {
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                tick.tick().await;
+                limiter.sweep();
+            }
+        });
+    }
+
+    // Reservation expiry: a crashed client must not hold budget forever
+    let st_exp = st.clone();
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tick.tick().await;
+            let now = Instant::now();
+            let mut b = st_exp.buckets.lock();
+            st_exp.reservations.lock().retain(|_, r| {
+                if r.expires > now { return true; }
+                st_exp.credit(b.entry(r.bucket.clone()).or_insert(0.0), r.joules);
+                false
+            });
+        }
+    });
+
+    // gzip/deflate per Accept-Encoding with JOULE_COMPRESSION=1; both off leaves bodies untouched.
+    // The default predicate skips SSE: an encoder would hold events back until its buffer fills
+    let compression = tower_http::compression::CompressionLayer::new().gzip(st.cfg.compression).deflate(st.cfg.compression);
+
     // HTTP API
     let app = Router::new()
         .route("/v1/sample", get({
             let st = st.clone();
-            move || async move {
+            move |Query(q): Query<SampleQuery>, headers: HeaderMap| async move {
                 let s = st.last.lock();
-                Json(serde_json::json!({
-                    "ts": s.ts,
-                    "gpu_w": s.gpu_w,
-                    "cpu_w": s.cpu_w,
-                    "idle_gpu_w": s.idle_gpu_w,
-                    "idle_cpu_w": s.idle_cpu_w,
-                    "net_w": s.net_w,
-                    "bucket_j": s.bucket_j,
-                    "hash": blake3::hash(format!("{}:{}", s.ts, s.bucket_j).as_bytes()).to_hex().to_string()
-                }))
+                if api_version(&st, &headers, q.api_version) == ApiVersion::V1 {
+                    let mut v = serde_json::to_value(LegacySample::from(&*s)).unwrap_or_default();
+                    if q.units != EnergyUnit::J { convert_energy(&mut v, q.units); }
+                    return Json(v);
+                }
+                let mut v = SampleResponse::from(&*s);
+                // opt-in detail, kept out of the default payload (and of the signed fields)
+                if q.cores.unwrap_or(0) != 0 {
+                    v.per_core_usage = Some(&s.per_core_usage);
+                    v.per_core_w = Some(&s.per_core_w);
+                }
+                // via Value: sorted keys, the same canonical order the signature is computed over
+                let mut v = serde_json::to_value(v).unwrap_or_default();
+                if q.units != EnergyUnit::J { convert_energy(&mut v, q.units); }
+                Json(v)
+            }
+        }).layer(compression.clone()))
+        .route("/v1/bucket", get({
+            let st = st.clone();
+            // just the balance, straight from the buckets rather than the last published sample
+            move |Query(q): Query<BucketQuery>| async move {
+                let bucket_j = st.buckets.lock().get(&q.bucket).copied().unwrap_or(0.0);
+                let ts = match st.cfg.clock {
+                    ClockMode::Mono => st.started.elapsed().as_millis() as f64 / 1000.0,
+                    ClockMode::Wall | ClockMode::Both => Utc::now().timestamp_millis() as f64 / 1000.0,
+                };
+                let mut v = serde_json::to_value(BucketResp { bucket: q.bucket, bucket_j, cap_j: st.cfg.bucket_cap_j, ts }).unwrap_or_default();
+                if q.units != EnergyUnit::J { convert_energy(&mut v, q.units); }
+                Json(v)
+            }
+        }))
+        .route("/v1/schema", get(|| async { Json(schemars::schema_for!(SampleResponse<'static>)) }))
+        .route("/openapi.json", get({
+            let spec = serde_json::to_value(openapi::spec()).unwrap_or_default();
+            move || async move { Json(spec) }
+        }))
+        .route("/docs", get({
+            let st = st.clone();
+            move || async move {
+                if !st.cfg.docs { return Err(not_found("the API docs page is off (JOULE_DOCS=1)")); }
+                Ok(Html(openapi::DOCS_HTML))
+            }
+        }))
+        .route("/v1/config", get({
+            let st = st.clone();
+            move || async move { Json(config_json(&st)) }
+        }).put({
+            let st = st.clone();
+            move |ApiJson(patch): ApiJson<TunablesPatch>| async move {
+                let mut tune = st.tune.lock();
+                let next = tune.apply(&patch);
+                next.validate().map_err(|e| bad_request(&e))?;
+                *tune = next;
+                drop(tune);
+                Ok::<_, ApiError>(Json(config_json(&st)))
+            }
+        }))
+        .route("/v1/history", get({
+            let st = st.clone();
+            move |Query(q): Query<HistoryQuery>, headers: HeaderMap| async move {
+                let since = q.since.unwrap_or(f64::NEG_INFINITY);
+                // the aggregates are on Unix time whatever JOULE_CLOCK says
+                if let Some(res @ (rollup::Resolution::Minute | rollup::Resolution::Hour)) = q.resolution {
+                    return Json(serde_json::to_value(st.rollups.lock().since(res, since)).unwrap_or_default());
+                }
+                let version = api_version(&st, &headers, q.api_version);
+                let h = st.history.lock();
+                Json(serde_json::Value::Array(h.iter().filter(|s| history_key(s) > since).map(|s| versioned_json(s, version)).collect()))
+            }
+        }).layer(compression.clone()))
+        .route("/v1/export", get({
+            let st = st.clone();
+            move |Query(q): Query<ExportQuery>, headers: HeaderMap| async move {
+                // from/to select like /v1/history's since: on the sample clock, Unix time for the aggregates
+                let from = q.from.unwrap_or(f64::NEG_INFINITY);
+                let to = q.to.unwrap_or(f64::INFINITY);
+                if from.is_nan() || to.is_nan() || from > to { return Err(bad_request("from must not be after to")); }
+                let fields: Option<HashSet<String>> = q.fields.map(|f| f.split(',').map(str::trim).filter(|f| !f.is_empty()).map(String::from).collect());
+                let version = api_version(&st, &headers, q.api_version);
+                // samples page by history sequence, which a clock step can't reorder, and stop at what
+                // was buffered when the request came in so new ones can't keep the stream open
+                let end_seq = st.history_seq.load(Ordering::Relaxed);
+                let now_unix = Utc::now().timestamp_millis() as f64 / 1000.0;
+                let cursor = ExportCursor { seq: 0, after_ts: from };
+                let lines = futures_util::stream::unfold((st, fields, cursor), move |(st, fields, mut cursor)| async move {
+                    let rows: Vec<serde_json::Value> = match q.resolution {
+                        rollup::Resolution::Sample => {
+                            // copy one chunk out under the lock, serialize after releasing it
+                            let mut chunk: Vec<Last> = Vec::new();
+                            {
+                                let h = st.history.lock();
+                                let front = st.history_seq.load(Ordering::Relaxed) - h.len() as u64;
+                                cursor.seq = cursor.seq.max(front);
+                                for s in h.iter().skip((cursor.seq - front) as usize) {
+                                    if cursor.seq >= end_seq || chunk.len() >= EXPORT_CHUNK { break; }
+                                    cursor.seq += 1;
+                                    let key = history_key(s);
+                                    if key > from && key <= to { chunk.push(s.clone()); }
+                                }
+                            }
+                            chunk.iter().map(|s| versioned_json(s, version)).collect()
+                        }
+                        res => {
+                            let chunk = st.rollups.lock().range(res, cursor.after_ts, to.min(now_unix), EXPORT_CHUNK);
+                            if let Some(p) = chunk.last() { cursor.after_ts = p.ts; }
+                            chunk.iter().map(|p| serde_json::to_value(p).unwrap_or_default()).collect()
+                        }
+                    };
+                    if rows.is_empty() { return None; }
+                    let mut out = String::new();
+                    for mut row in rows {
+                        if let (Some(keep), serde_json::Value::Object(obj)) = (&fields, &mut row) { obj.retain(|k, _| keep.contains(k)); }
+                        out.push_str(&row.to_string());
+                        out.push('\n');
+                    }
+                    Some((Ok::<_, std::convert::Infallible>(out), (st, fields, cursor)))
+                });
+                Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], axum::body::Body::from_stream(lines)))
+            }
+        }).layer(compression.clone()))
+        .route("/v1/recent", get({
+            let st = st.clone();
+            move |Query(q): Query<RecentQuery>, headers: HeaderMap| async move {
+                let version = api_version(&st, &headers, q.api_version);
+                // clone just the tail so the sampler isn't held up by the serialization
+                let recent: Vec<Last> = {
+                    let h = st.history.lock();
+                    h.iter().skip(h.len().saturating_sub(q.n.unwrap_or(30))).cloned().collect()
+                };
+                Json(serde_json::Value::Array(recent.iter().map(|s| versioned_json(s, version)).collect()))
+            }
+        }).layer(compression.clone()))
+        .route("/v1/health", get({
+            let st = st.clone();
+            move || async move {
+                let age = st.last_tick.lock().elapsed().as_secs_f64();
+                let nvml_ok = st.last.lock().nvml_healthy;
+                // a few missed periods means the sampler is wedged; let the orchestrator restart us
+                let tune = *st.tune.lock();
+                let stale_after = (3.0 / tune.publish_hz.unwrap_or(tune.hz)).max(2.0);
+                let ok = age <= stale_after;
+                let body = serde_json::json!({
+                    "status": if ok { "ok" } else { "stalled" },
+                    "uptime_s": st.started.elapsed().as_secs_f64(),
+                    "nvml": nvml_ok,
+                    "last_sample_age_s": age,
+                });
+                (if ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE }, Json(body))
+            }
+        }))
+        .route("/v1/powercap", get({
+            let (nvml, devices) = (nvml.clone(), metered_gpus.clone());
+            move || async move {
+                let caps = nvml.lock().as_ref().map(|n| powercap::limits(n, &devices)).ok_or_else(no_nvml)?;
+                caps.map(|c| Json(serde_json::json!({ "devices": c }))).map_err(powercap_err)
+            }
+        }).post({
+            let (st, nvml, devices) = (st.clone(), nvml.clone(), metered_gpus);
+            move |ApiJson(req): ApiJson<PowerCapReq>| async move {
+                // changes hardware state: never open, even where the rest of the API is
+                if st.cfg.api_token.is_none() {
+                    return Err(ApiError::new(StatusCode::FORBIDDEN, "forbidden", "power capping requires JOULE_API_TOKEN"));
+                }
+                let targets: Vec<u32> = match req.gpu {
+                    Some(i) if devices.contains(&i) => vec![i],
+                    Some(i) => return Err(bad_request(&format!("GPU {} is not metered", i))),
+                    None => devices.clone(),
+                };
+                let caps = nvml.lock().as_ref().map(|n| powercap::set(n, &targets, req.watts)).ok_or_else(no_nvml)?;
+                let caps = caps.map_err(powercap_err)?;
+                tracing::info!(watts = req.watts, devices = ?targets, "GPU power cap set");
+                Ok(Json(serde_json::json!({ "ok": true, "devices": caps })))
+            }
+        }))
+        .route("/v1/top", get({
+            let st = st.clone();
+            move |Query(q): Query<TopQuery>| async move {
+                let s = st.last.lock().clone();
+                let n = q.n.unwrap_or(10);
+                let (cpu_w, gpu_w) = (s.cpu_w, s.gpu_w);
+                let (cpu, gpu) = tokio::task::spawn_blocking(move || top::top(n, s.cpu_w, &s.per_gpu_w, &s.gpu_devices, &nvml))
+                    .await
+                    .unwrap_or_default();
+                Json(serde_json::json!({ "ts": s.ts, "cpu_w": cpu_w, "gpu_w": gpu_w, "cpu": cpu, "gpu": gpu, "method": top::METHOD }))
+            }
+        }))
+        .route("/v1/diag", get({
+            let st = st.clone();
+            move || async move { Json(st.diag.lock().report()) }
+        }))
+        .route("/v1/verify", get({
+            let st = st.clone();
+            move || async move {
+                if st.cfg.hash_algo == chain::HashAlgo::None { return Err(not_found("the hash chain is disabled (JOULE_HASH_ALGO=none)")); }
+                Ok(Json(verify_chain(st.cfg.hash_algo, &st.history.lock())))
+            }
+        }))
+        .route("/v1/pubkey", get({
+            let st = st.clone();
+            move || async move {
+                match &st.signer {
+                    Some(key) => Ok(Json(serde_json::json!({ "alg": "ed25519", "public_key": sign::public_key_hex(key) }))),
+                    None => Err(not_found("sample signing is not configured")),
+                }
+            }
+        }))
+        .route("/v1/stream", get({
+            let st = st.clone();
+            move |Query(q): Query<StreamQuery>, headers: HeaderMap| async move {
+                let version = api_version(&st, &headers, q.api_version);
+                let events = BroadcastStream::new(st.samples_tx.subscribe()).map(move |s| match s {
+                    Ok(s) => Event::default().json_data(versioned_json(&s, version)),
+                    // the channel already dropped this client's oldest samples; say how many
+                    Err(BroadcastStreamRecvError::Lagged(n)) => Event::default().event("lagged").json_data(serde_json::json!({ "missed": n })),
+                });
+                // end open streams on shutdown, otherwise graceful shutdown waits on them forever
+                let events = futures_util::StreamExt::take_until(events, shutdown_requested(st.shutdown.clone()));
+                Sse::new(events).keep_alive(KeepAlive::default())
+            }
+        }))
+        .route("/v1/ws", get({
+            let st = st.clone();
+            move |upgrade: WebSocketUpgrade, ConnectInfo(peer): ConnectInfo<SocketAddr>, Query(q): Query<StreamQuery>, headers: HeaderMap| async move {
+                let client = rate_client(&st, &headers, peer);
+                let version = api_version(&st, &headers, q.api_version);
+                upgrade.on_upgrade(move |socket| ws::serve(socket, st, client, version))
+            }
+        }))
+        .route("/metrics", get({
+            let st = st.clone();
+            move || async move {
+                let (all, hist) = scrape(&st);
+                let labels = [("host", &*st.host), ("machine_id", &*st.machine_id)];
+                let mut body = metrics::render(&all, &labels);
+                body.push_str(&metrics::render_histogram(&hist, &labels));
+                ([(axum::http::header::CONTENT_TYPE, metrics::CONTENT_TYPE)], body)
             }
         }))
         .route("/v1/take", post({
             let st = st.clone();
-            move |Json(req): Json<TakeReq>| async move {
-                let mut b = st.bucket_j.lock();
-                if *b >= req.joules { *b -= req.joules; Json(TakeResp { ok: true, remaining_j: *b }) }
-                else { Json(TakeResp { ok: false, remaining_j: *b }) }
+            move |ApiJson(req): ApiJson<TakeReq>| async move {
+                check_warm(&st)?;
+                // a negative take would mint energy and NaN would wedge the bucket for good
+                check_joules(req.joules)?;
+                let r = match req.wait_ms {
+                    Some(ms) if ms > 0 => st.take_waiting(&req.bucket, req.joules, req.allow_partial, Duration::from_millis(ms)).await,
+                    _ => st.take(&req.bucket, req.joules, req.allow_partial),
+                };
+                if !r.ok { return Err(insufficient(&req.bucket, req.joules, r.remaining_j)); }
+                Ok(Json(r))
+            }
+        }))
+        .route("/v1/can_take", get({
+            let st = st.clone();
+            // what /v1/take would answer right now, without touching the bucket or the take counters
+            move |Query(q): Query<CanTakeQuery>| async move {
+                check_joules(q.joules)?;
+                let have = st.buckets.lock().get(&q.bucket).copied().unwrap_or(0.0);
+                let ok = have >= q.joules;
+                Ok::<_, ApiError>(Json(CanTakeResp {
+                    ok,
+                    remaining_j: if ok { have - q.joules } else { have },
+                    shortfall_j: (q.joules - have).max(0.0),
+                }))
+            }
+        }))
+        .route("/v1/takes", get({
+            let st = st.clone();
+            move |Query(q): Query<TakesQuery>| async move { Json(st.take_log.lock().recent(q.limit.unwrap_or(100))) }
+        }))
+        .route("/v1/take_stats", get({
+            let st = st.clone();
+            move || async move { Json(st.take_hist.lock().summary()) }
+        }))
+        .route("/v1/take_batch", post({
+            let st = st.clone();
+            move |ApiJson(req): ApiJson<TakeBatchReq>| async move {
+                check_warm(&st)?;
+                for item in &req.items { check_joules(item.joules)?; }
+                Ok::<_, ApiError>(Json(TakeBatchResp { ok: true, remaining_j: st.take_batch(&req.items)? }))
+            }
+        }))
+        .route("/v1/admit", post({
+            let st = st.clone();
+            move |ApiJson(req): ApiJson<AdmitReq>| async move {
+                check_joules(req.est_joules)?;
+                let balance = st.buckets.lock().get(&req.bucket).copied().unwrap_or(0.0);
+                let policy = admit::Policy {
+                    priority_threshold: st.cfg.admit_priority_threshold,
+                    high_water_j: st.cfg.admit_high_water_j,
+                    cap_j: st.cfg.bucket_cap_j,
+                };
+                // the bucket's share of each refill, as it would be split by integrate/set_total
+                let share = st.bucket_share(&req.bucket);
+                let refill = st.cfg.refill.map(|r| match r.mode {
+                    RefillMode::Add => admit::Refill::Add { joules_per_s: r.joules * share / r.interval_s },
+                    RefillMode::Set => admit::Refill::Set {
+                        joules: (r.joules * share).min(st.cfg.bucket_cap_j.unwrap_or(f64::INFINITY)),
+                        next_in_s: st.next_refill.lock().map_or(r.interval_s, |t| t.saturating_duration_since(Instant::now()).as_secs_f64()),
+                    },
+                });
+                Ok::<_, ApiError>(Json(admit::decide(&policy, refill.as_ref(), balance, req.est_joules, req.priority)))
+            }
+        }))
+        .route("/v1/refund", post({
+            let st = st.clone();
+            move |ApiJson(req): ApiJson<RefundReq>| async move {
+                check_joules(req.joules)?;
+                Ok::<_, ApiError>(Json(st.refund(&req.bucket, req.joules)))
+            }
+        }))
+        .route("/v1/reserve", post({
+            let st = st.clone();
+            move |ApiJson(req): ApiJson<ReserveReq>| async move {
+                check_warm(&st)?;
+                check_joules(req.joules)?;
+                let ttl_s = req.ttl_s.unwrap_or(st.cfg.reservation_ttl_s);
+                if !ttl_s.is_finite() || ttl_s <= 0.0 {
+                    return Err(bad_request("ttl_s must be a positive number"));
+                }
+                let mut buckets = st.buckets.lock();
+                let b = buckets.entry(req.bucket.clone()).or_insert(0.0);
+                if *b < req.joules { return Err(insufficient(&req.bucket, req.joules, *b)); }
+                *b -= req.joules;
+                let id = st.next_reservation.fetch_add(1, Ordering::Relaxed);
+                let expires = Instant::now() + Duration::from_secs_f64(ttl_s);
+                st.reservations.lock().insert(id, Reservation { bucket: req.bucket, joules: req.joules, expires });
+                Ok(Json(ReserveResp { ok: true, id, remaining_j: *b, ttl_s }))
+            }
+        }))
+        .route("/v1/commit/:id", post({
+            let st = st.clone();
+            move |axum::extract::Path(id): axum::extract::Path<u64>| async move {
+                // the joules already left the bucket at reserve time; committing just drops the hold
+                let buckets = st.buckets.lock();
+                match st.reservations.lock().remove(&id) {
+                    Some(r) => {
+                        let remaining_j = buckets.get(&r.bucket).copied().unwrap_or(0.0);
+                        Ok(Json(SettleResp { ok: true, joules: r.joules, remaining_j }))
+                    }
+                    None => Err(not_found("unknown or expired reservation")),
+                }
+            }
+        }))
+        .route("/v1/calibrate", post({
+            let st = st.clone();
+            move |Query(q): Query<CalibrateQuery>| async move {
+                let window_s = q.window_s.unwrap_or(st.cfg.calibrate_window_s);
+                if !window_s.is_finite() || window_s <= 0.0 || window_s > 3600.0 {
+                    return Err(bad_request("window_s must be in (0, 3600]"));
+                }
+                let (gpu, cpu, wall, dram, n) = calibrate(&st, Duration::from_secs_f64(window_s)).await
+                    .ok_or_else(|| unavailable("no samples during the calibration window"))?;
+                *st.idle_gpu_w.lock() = gpu;
+                *st.idle_cpu_w.lock() = cpu;
+                if wall.is_some() { *st.idle_wall_w.lock() = wall; }
+                if dram.is_some() { *st.idle_dram_w.lock() = dram; }
+                tracing::info!(idle_gpu_w = gpu, idle_cpu_w = cpu, idle_wall_w = ?wall, idle_dram_w = ?dram, samples = n, "idle baselines calibrated");
+                Ok(Json(CalibrateResp { ok: true, samples: n, idle_gpu_w: gpu, idle_cpu_w: cpu, idle_wall_w: wall, idle_dram_w: dram }))
+            }
+        }))
+        .route("/v1/calibrate/reset", post({
+            let st = st.clone();
+            move || async move {
+                *st.idle_gpu_w.lock() = st.cfg.idle_gpu_w;
+                *st.idle_cpu_w.lock() = st.cfg.idle_cpu_w;
+                // no configured wall or DRAM default: re-seed from the next reading
+                *st.idle_wall_w.lock() = None;
+                *st.idle_dram_w.lock() = None;
+                tracing::info!(idle_gpu_w = st.cfg.idle_gpu_w, idle_cpu_w = st.cfg.idle_cpu_w, "idle baselines reset");
+                Json(CalibrateResp { ok: true, samples: 0, idle_gpu_w: st.cfg.idle_gpu_w, idle_cpu_w: st.cfg.idle_cpu_w, idle_wall_w: None, idle_dram_w: None })
+            }
+        }))
+        .route("/v1/extremes/reset", post({
+            let st = st.clone();
+            move || async move {
+                *st.extremes.lock() = extremes::Extremes::default();
+                tracing::info!("power extremes reset");
+                Json(serde_json::json!({ "ok": true }))
+            }
+        }))
+        .route("/v1/pause", post({
+            let st = st.clone();
+            move || async move {
+                if !st.paused.swap(true, Ordering::Relaxed) { tracing::info!("accounting paused"); }
+                Json(serde_json::json!({ "ok": true, "paused": true }))
+            }
+        }))
+        .route("/v1/resume", post({
+            let st = st.clone();
+            move || async move {
+                if st.paused.swap(false, Ordering::Relaxed) { tracing::info!("accounting resumed"); }
+                Json(serde_json::json!({ "ok": true, "paused": false }))
+            }
+        }))
+        .route("/v1/release/:id", post({
+            let st = st.clone();
+            move |axum::extract::Path(id): axum::extract::Path<u64>| async move {
+                let mut buckets = st.buckets.lock();
+                match st.reservations.lock().remove(&id) {
+                    Some(r) => {
+                        let b = buckets.entry(r.bucket).or_insert(0.0);
+                        st.credit(b, r.joules);
+                        Ok(Json(SettleResp { ok: true, joules: r.joules, remaining_j: *b }))
+                    }
+                    None => Err(not_found("unknown or expired reservation")),
+                }
+            }
+        }))
+        // mistyped paths get the error envelope too, not an empty 404
+        .fallback(|method: Method, uri: Uri| async move { not_found(&format!("no route for {} {}", method, uri.path())) })
+        .layer(middleware::from_fn_with_state(st.clone(), rate_limit))
+        .layer(middleware::from_fn_with_state(st.clone(), require_token));
+    // around the whole router rather than each route: axum adds the Allow header outside route layers
+    let app = Router::new().fallback_service(app).layer(middleware::from_fn(method_not_allowed));
+    // outermost, so preflights are answered before the token gate sees them
+    let app = match &st.cfg.cors_origins {
+        Some(origins) => app.layer(cors_layer(origins)),
+        None => app,
+    };
+
+    let addr = SocketAddr::new(st.cfg.bind_addr, st.cfg.port);
+    // bind a TcpListener and serve via axum::serve for compatibility. A supervisor restarting us
+    // often races the old process for the port, so retry a few times before giving up
+    let listener = match bind_with_retry(addr, st.cfg.bind_attempts, Duration::from_secs_f64(st.cfg.bind_retry_delay_s)).await {
+        Ok(l) => l,
+        Err(e) => {
+            tracing::error!(%addr, error = %e, attempts = st.cfg.bind_attempts, "cannot bind, giving up");
+            std::process::exit(EXIT_BIND_FAILED)
+        }
+    };
+    // report the real address: JOULE_PORT=0 lets the OS pick the port
+    tracing::info!(addr = %listener.local_addr().unwrap_or(addr), "listening");
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            let _ = shutdown_tx.send(true);
+        })
+        .await
+        .unwrap_or_else(|e| tracing::error!(error = %e, "server error"));
+    let _ = sampler.await;
+    for sink in sinks { let _ = sink.await; }
+    // final export; shutdown blocks on the reader thread, so keep it off the async workers
+    #[cfg(feature = "otel")]
+    if let Some(provider) = otel_provider {
+        let _ = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+    }
+    if st.cfg.shutdown_reservations == ShutdownReservations::Release { st.release_all(); }
+    st.flush_state();
+    tracing::info!(
+        uptime_s = st.started.elapsed().as_secs_f64(),
+        integrated_j = *st.total_integrated_j.lock(),
+        bucket_j = st.buckets.lock().values().sum::<f64>(),
+        "stopped",
+    );
+}
+
+// JOULE_LOG (or RUST_LOG) filters levels, default info; JOULE_LOG_FORMAT=json for log shippers
+fn init_logging() {
+    use tracing_subscriber::EnvFilter;
+    let filter = config::lookup("JOULE_LOG")
+        .and_then(|f| EnvFilter::try_new(f).ok())
+        .or_else(|| EnvFilter::try_from_default_env().ok())
+        .unwrap_or_else(|| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if config::lookup("JOULE_LOG_FORMAT").as_deref() == Some("json") { builder.json().init(); } else { builder.init(); }
+}
+
+// mean gpu_w/cpu_w (and wall_w when a plug is live) over the window, ignoring the idle-learning
+// gate; None if no sample arrived
+async fn calibrate(st: &State, window: Duration) -> Option<(f64, f64, Option<f64>, Option<f64>, usize)> {
+    let mut rx = st.samples_tx.subscribe();
+    let deadline = tokio::time::sleep(window);
+    tokio::pin!(deadline);
+    let (mut gpu, mut cpu, mut n) = (0.0, 0.0, 0usize);
+    let (mut wall, mut wall_n) = (0.0, 0usize);
+    let (mut dram, mut dram_n) = (0.0, 0usize);
+    loop {
+        tokio::select! {
+            r = rx.recv() => match r {
+                Ok(s) if !s.warmup => {
+                    gpu += s.gpu_w; cpu += s.cpu_w; n += 1;
+                    if let Some(w) = s.wall_w { wall += w; wall_n += 1; }
+                    if let Some(w) = s.dram_w { dram += w; dram_n += 1; }
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = &mut deadline => break,
+            _ = shutdown_requested(st.shutdown.clone()) => break,
+        }
+    }
+    if n == 0 { return None; }
+    let wall = (wall_n > 0).then(|| wall / wall_n as f64);
+    let dram = (dram_n > 0).then(|| dram / dram_n as f64);
+    Some((gpu / n as f64, cpu / n as f64, wall, dram, n))
+}
+
+async fn csv_sink(st: State, mut log: csvlog::CsvLog) {
+    let mut rx = st.samples_tx.subscribe();
+    let mut flush = tokio::time::interval(Duration::from_secs_f64(st.cfg.csv_flush_s.max(0.1)));
+    loop {
+        tokio::select! {
+            r = rx.recv() => match r {
+                Ok(s) => {
+                    let row = csvlog::Row {
+                        ts: s.ts, gpu_w: s.gpu_w, cpu_w: s.cpu_w, compute_net_w: s.compute_net_w,
+                        idle_gpu_w: s.idle_gpu_w, idle_cpu_w: s.idle_cpu_w, bucket_j: s.bucket_j,
+                    };
+                    if let Err(e) = log.append(&row) { tracing::warn!(error = %e, "CSV write failed"); }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => tracing::warn!(missed = n, "CSV log fell behind"),
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = flush.tick() => { if let Err(e) = log.flush() { tracing::warn!(error = %e, "CSV flush failed"); } }
+            _ = shutdown_requested(st.shutdown.clone()) => break,
+        }
+    }
+    let _ = log.flush();
+}
+
+// SIGINT everywhere, plus SIGTERM on unix (the normal stop signal under systemd/k8s)
+async fn shutdown_signal() {
+    let ctrl_c = async { let _ = tokio::signal::ctrl_c().await; };
+    #[cfg(unix)]
+    let term = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut s) => { s.recv().await; }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let term = std::future::pending::<()>();
+    tokio::select! { _ = ctrl_c => {}, _ = term => {} }
+}
+
+async fn shutdown_requested(mut rx: watch::Receiver<bool>) {
+    let _ = rx.wait_for(|stop| *stop).await;
+}
+
+// Bearer-token gate applied to the whole router: every non-GET route is mutating and needs the
+// token; reads only when JOULE_PROTECT_READS is set
+async fn require_token(AxState(st): AxState<State>, req: Request, next: Next) -> Response {
+    let Some(token) = &st.cfg.api_token else { return next.run(req).await };
+    // probes can't be expected to carry the token, and health reveals nothing sensitive
+    if req.uri().path() == "/v1/health" { return next.run(req).await; }
+    // the WebSocket upgrade is a GET, but the socket can take and refund
+    let is_read = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) && req.uri().path() != "/v1/ws";
+    if is_read && !st.cfg.protect_reads { return next.run(req).await; }
+    match bearer_token(req.headers()) {
+        Some(p) if constant_time_eq(p.as_bytes(), token.as_bytes()) => next.run(req).await,
+        _ => ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", "missing or invalid bearer token").into_response(),
+    }
+}
+
+// axum answers a known path with the wrong method by itself (405, an Allow header, no body); give
+// that the error envelope as well, with the allowed methods listed
+async fn method_not_allowed(req: Request, next: Next) -> Response {
+    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+    let resp = next.run(req).await;
+    if resp.status() != StatusCode::METHOD_NOT_ALLOWED { return resp; }
+    let allow = resp.headers().get(header::ALLOW).cloned();
+    let allowed: Vec<&str> = allow.as_ref().and_then(|v| v.to_str().ok()).map_or_else(Vec::new, |v| v.split(',').map(str::trim).collect());
+    let err = ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", format!("{} is not allowed on {}", method, path)).with("allowed", allowed);
+    let mut out = err.into_response();
+    if let Some(v) = allow { out.headers_mut().insert(header::ALLOW, v); }
+    out
+}
+
+// spending endpoints only; reads such as /v1/sample and /metrics are never limited
+async fn rate_limit(AxState(st): AxState<State>, ConnectInfo(peer): ConnectInfo<SocketAddr>, req: Request, next: Next) -> Response {
+    let Some(limiter) = &st.take_limiter else { return next.run(req).await };
+    if !matches!(req.uri().path(), "/v1/take" | "/v1/take_batch" | "/v1/reserve") { return next.run(req).await; }
+    match limiter.check(rate_client(&st, req.headers(), peer)) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            let retry_after = wait.as_secs_f64().ceil().max(1.0).to_string();
+            let err = ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited", "rate limit exceeded").with("retry_after_s", wait.as_secs_f64());
+            ([(header::RETRY_AFTER, retry_after)], err).into_response()
+        }
+    }
+}
+
+// browsers may read (GET/HEAD) from the configured origins, sending the bearer token when reads
+// are protected; mutating endpoints stay same-origin because their preflight is refused
+fn cors_layer(origins: &[String]) -> tower_http::cors::CorsLayer {
+    use tower_http::cors::{AllowOrigin, CorsLayer};
+    let allow = if origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().filter_map(|o| match o.parse() {
+            Ok(v) => Some(v),
+            Err(_) => {
+                tracing::warn!(origin = %o, "ignoring invalid JOULE_CORS_ORIGINS entry");
+                None
             }
-        }));
+        }))
+    };
+    CorsLayer::new()
+        .allow_origin(allow)
+        .allow_methods([Method::GET, Method::HEAD])
+        .allow_headers([header::AUTHORIZATION, header::ACCEPT, header::CONTENT_TYPE])
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")
+}
+
+// holders of the configured token share its budget wherever they connect from; anyone else is keyed
+// by address, since an unchecked token could be rotated for a fresh bucket on every request
+fn rate_client(st: &State, headers: &HeaderMap, peer: SocketAddr) -> ratelimit::Client {
+    match (&st.cfg.api_token, bearer_token(headers)) {
+        (Some(token), Some(t)) if constant_time_eq(t.as_bytes(), token.as_bytes()) => ratelimit::Client::Token(*blake3::hash(t.as_bytes()).as_bytes()),
+        _ => ratelimit::Client::Ip(peer.ip()),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 8787));
-    println!("[JouleAgent] listening on http://{}", addr);
-    // bind a TcpListener and serve via axum::serve for compatibility
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+// every HTTP error: the status plus {"error": {"code", "message"}}, where `code` is a stable
+// machine-readable name and any `details` sit alongside (e.g. remaining_j on a denied take)
+struct ApiError { status: StatusCode, code: &'static str, message: String, details: serde_json::Map<String, serde_json::Value> }
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError { status, code, message: message.into(), details: serde_json::Map::new() }
+    }
+
+    fn with(mut self, key: &str, value: impl Serialize) -> Self {
+        self.details.insert(key.to_string(), serde_json::to_value(value).unwrap_or_default());
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut error = self.details;
+        error.insert("code".into(), self.code.into());
+        error.insert("message".into(), self.message.into());
+        (self.status, Json(serde_json::json!({ "error": error }))).into_response()
+    }
+}
+
+// Json, but a body that doesn't parse is a 400 in the ApiError envelope rather than axum's
+// plain-text 422
+struct ApiJson<T>(T);
+
+#[axum::async_trait]
+impl<T: serde::de::DeserializeOwned, S: Send + Sync> FromRequest<S> for ApiJson<T> {
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, ApiError> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(v)) => Ok(ApiJson(v)),
+            Err(e) if e.status() == StatusCode::UNSUPPORTED_MEDIA_TYPE => Err(ApiError::new(e.status(), "unsupported_media_type", e.body_text())),
+            Err(e) => Err(bad_request(&e.body_text())),
+        }
+    }
+}
+
+// 503 while JOULE_WARMUP_S runs: the balance is not backed by a learned baseline yet
+fn check_warm(st: &State) -> Result<(), ApiError> {
+    let left = st.warmup_left_s();
+    if left <= 0.0 { return Ok(()); }
+    Err(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "warming_up", "the agent is warming up; takes are refused until it ends").with("retry_after_s", left))
 }
 
-fn env_f(key: &str, def: f64) -> f64 {
-    std::env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(def)
+fn check_joules(joules: f64) -> Result<(), ApiError> {
+    if joules.is_finite() && joules >= 0.0 { Ok(()) } else { Err(bad_request("joules must be a finite, non-negative number")) }
 }
+
+fn bad_request(msg: &str) -> ApiError { ApiError::new(StatusCode::BAD_REQUEST, "invalid_input", msg) }
+fn not_found(msg: &str) -> ApiError { ApiError::new(StatusCode::NOT_FOUND, "not_found", msg) }
+fn unavailable(msg: &str) -> ApiError { ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "unavailable", msg) }
+fn no_nvml() -> ApiError { unavailable("NVML is unavailable") }
+
+// 402: the bucket can't cover the request; `remaining_j` is what it holds
+fn insufficient(bucket: &str, requested_j: f64, remaining_j: f64) -> ApiError {
+    ApiError::new(StatusCode::PAYMENT_REQUIRED, "insufficient_balance", format!("bucket {:?} holds {} J, {} J requested", bucket, remaining_j, requested_j))
+        .with("bucket", bucket)
+        .with("requested_j", requested_j)
+        .with("remaining_j", remaining_j)
+}
+
+fn powercap_err(e: powercap::CapErr) -> ApiError {
+    let (status, code) = match e {
+        powercap::CapErr::OutOfRange(_) => (StatusCode::BAD_REQUEST, "invalid_input"),
+        powercap::CapErr::Permission => (StatusCode::FORBIDDEN, "forbidden"),
+        powercap::CapErr::Unsupported(_) => (StatusCode::NOT_IMPLEMENTED, "not_supported"),
+        powercap::CapErr::Nvml(_) => (StatusCode::INTERNAL_SERVER_ERROR, "nvml_error"),
+    };
+    ApiError::new(status, code, e.to_string())
+}
+
+// effective configuration: live tunables plus the fixed startup settings
+fn config_json(st: &State) -> serde_json::Value {
+    #[derive(Serialize)]
+    struct View<'a> { #[serde(flatten)] tune: Tunables, cpu_curve: Option<Vec<(f64, f64)>>, #[serde(flatten)] cfg: &'a Cfg }
+    serde_json::to_value(View { tune: *st.tune.lock(), cpu_curve: st.cpu_curve.lock().clone(), cfg: &st.cfg }).unwrap_or_default()
+}
+
+// walk the history and check every hash and every link; the oldest entry's parent may already be evicted
+fn verify_chain(algo: chain::HashAlgo, history: &VecDeque<Last>) -> serde_json::Value {
+    let mut prev: Option<&Last> = None;
+    for (i, s) in history.iter().enumerate() {
+        let linked = prev.is_none_or(|p| p.hash == s.prev_hash);
+        if !linked || s.hash != algo.chain(&s.prev_hash, s.ts, s.compute_net_w, s.bucket_j) {
+            return serde_json::json!({ "ok": false, "checked": i + 1, "broken_at_ts": s.ts });
+        }
+        prev = Some(s);
+    }
+    serde_json::json!({ "ok": true, "checked": history.len(), "broken_at_ts": null })
+}
+
+// shared payload for /v1/sample, /v1/history, /v1/stream and MQTT; also what the signature covers
+#[derive(Serialize, schemars::JsonSchema, utoipa::ToSchema)]
+struct SampleResponse<'a> {
+    /// Unix time, or seconds since agent start with JOULE_CLOCK=mono.
+    ts: f64,
+    /// Seconds since agent start; only with JOULE_CLOCK=both.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mono_ts: Option<f64>,
+    host: &'a str,
+    /// Stable node identity: JOULE_NODE_ID, else /etc/machine-id, else the hostname.
+    machine_id: &'a str,
+    /// Smoothed with JOULE_POWER_SMOOTHING, like cpu_w, per_gpu_w and per_socket_w; what is integrated.
+    gpu_w: f64,
+    /// The reading before JOULE_POWER_SMOOTHING; equal to gpu_w with it off.
+    gpu_w_raw: f64,
+    per_gpu_w: &'a [f64],
+    gpu_vendors: &'a [&'static str],
+    /// NVML indices being metered (JOULE_GPU_INCLUDE / JOULE_GPU_EXCLUDE).
+    gpu_devices: &'a [u32],
+    nvml_healthy: bool,
+    /// Across NVIDIA devices: mean utilization, summed memory, hottest temperature; null without NVML.
+    gpu_util_pct: Option<f64>,
+    gpu_mem_used_mb: Option<f64>,
+    gpu_mem_total_mb: Option<f64>,
+    gpu_temp_c: Option<u32>,
+    /// Summed enforced NVML power limit (see POST /v1/powercap); null without NVML.
+    gpu_power_cap_w: Option<f64>,
+    per_gpu_stats: &'a [GpuStats],
+    /// Clocks held down by heat or a power limit on some GPU or CPU: low power here isn't idleness.
+    throttled: bool,
+    /// `gpu<index>:<reason>` (sw_power_cap, hw_slowdown, sw_thermal_slowdown, hw_thermal_slowdown,
+    /// hw_power_brake_slowdown), and `cpu:thermal` when a Linux thermal_throttle counter moved.
+    throttle_reasons: &'a [String],
+    cpu_w: f64,
+    /// The reading before JOULE_POWER_SMOOTHING; equal to cpu_w with it off.
+    cpu_w_raw: f64,
+    /// cpu_w per socket: RAPL package domains, else the estimate split by the JOULE_CPU_TDP_W list.
+    per_socket_w: &'a [f64],
+    /// Only with `?cores=1`; not covered by the signature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    per_core_usage: Option<&'a [f32]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    per_core_w: Option<&'a [f64]>,
+    idle_gpu_w: f64,
+    idle_cpu_w: f64,
+    wall_w: Option<f64>,
+    idle_wall_w: Option<f64>,
+    /// Memory power from the RAPL DRAM domains, with JOULE_DRAM=1 where the platform exposes them.
+    /// Its share above idle_dram_w is part of compute_net_w.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dram_w: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    idle_dram_w: Option<f64>,
+    model_error_w: Option<f64>,
+    compute_net_w: f64,
+    /// Power integrated into the bucket (plus network_w): compute_net_w, or gross power with JOULE_METER_MODE=gross.
+    metered_w: f64,
+    meter_mode: MeterMode,
+    network_w: f64,
+    /// The agent's own share of the CPU power above idle; subtracted from compute_net_w with JOULE_SUBTRACT_SELF=1.
+    agent_self_w: f64,
+    bucket_j: f64,
+    /// bucket_j in watt-hours, whatever `?units=` says.
+    bucket_wh: f64,
+    buckets: &'a BTreeMap<String, f64>,
+    spilled_j: f64,
+    /// Every joule ever integrated (kept across restarts with JOULE_STATE_FILE); takes and refunds don't touch it.
+    total_integrated_j: f64,
+    cpu_source: &'static str,
+    /// CPU usage was measured against the container's cgroup v2 CPU quota instead of the whole host.
+    cgroup_aware: bool,
+    /// Sources this sample was read from: every GPU source with a reading, then the CPU source used.
+    power_sources: &'a [&'static str],
+    /// `measured` when every reading came from a sensor (or a live plug), `estimated` when the CPU side was
+    /// modelled (TDP, curve, PDH), `unavailable` when no source returned data this tick.
+    power_quality: sources::Quality,
+    warmup: bool,
+    /// Accounting paused with POST /v1/pause: power is live, but nothing is integrated or learned.
+    paused: bool,
+    /// Within JOULE_WARMUP_S of start: the idle baselines are still being learned, nothing is
+    /// integrated and takes are refused. Metering is trustworthy once this is false.
+    warming_up: bool,
+    /// Seconds of warm-up left; 0 once it is over.
+    warmup_remaining_s: f64,
+    /// Share of the last JOULE_DUTY_WINDOW_S seconds with compute_net_w above JOULE_ACTIVE_W, 0..100;
+    /// null before the first tick.
+    active_duty_pct: Option<f64>,
+    /// Seconds since start spent above / at or below JOULE_ACTIVE_W (suspend gaps not counted).
+    active_s: f64,
+    idle_s: f64,
+    /// Highest and lowest gpu_w, cpu_w and compute_net_w (as net_w) since start or POST /v1/extremes/reset,
+    /// each with its Unix time; null until the first tick.
+    extremes: extremes::Extremes,
+    /// Ticks not integrated because a suspend/resume gap was detected, since start.
+    suspend_gaps: u64,
+    /// Nominal seconds between published samples: 1 / JOULE_PUBLISH_HZ, else 1 / JOULE_HZ.
+    period_s: f64,
+    /// Measured seconds since the previous published sample, for integrating on the client side;
+    /// drifts above period_s when the sampler slips under load. Null on the first sample.
+    actual_dt_s: Option<f64>,
+    battery_w: Option<f64>,
+    on_ac: Option<bool>,
+    refill_in_s: Option<f64>,
+    cost_usd: f64,
+    co2_g: f64,
+    cost_rate_usd_per_h: f64,
+    co2_g_per_kwh: f64,
+    #[schema(value_type = Object)]
+    stats: &'a serde_json::Map<String, serde_json::Value>,
+    /// Chains onto prev_hash, computed with hash_algo; all three omitted with JOULE_HASH_ALGO=none.
+    #[serde(skip_serializing_if = "str::is_empty")]
+    hash: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    prev_hash: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash_algo: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sig: Option<&'a str>,
+}
+
+impl<'a> From<&'a Last> for SampleResponse<'a> {
+    fn from(s: &'a Last) -> Self {
+        SampleResponse {
+            ts: s.ts,
+            mono_ts: s.mono_ts,
+            host: &s.host,
+            machine_id: &s.machine_id,
+            gpu_w: s.gpu_w,
+            gpu_w_raw: s.gpu_w_raw,
+            per_gpu_w: &s.per_gpu_w,
+            gpu_vendors: &s.gpu_vendors,
+            gpu_devices: &s.gpu_devices,
+            nvml_healthy: s.nvml_healthy,
+            gpu_util_pct: mean(s.per_gpu_stats.iter().filter_map(|g| g.util_pct.map(f64::from))),
+            gpu_mem_used_mb: sum(s.per_gpu_stats.iter().filter_map(|g| g.mem_used_mb)),
+            gpu_mem_total_mb: sum(s.per_gpu_stats.iter().filter_map(|g| g.mem_total_mb)),
+            gpu_temp_c: s.per_gpu_stats.iter().filter_map(|g| g.temp_c).max(),
+            gpu_power_cap_w: sum(s.per_gpu_stats.iter().filter_map(|g| g.power_cap_w)),
+            per_gpu_stats: &s.per_gpu_stats,
+            throttled: !s.throttle_reasons.is_empty(),
+            throttle_reasons: &s.throttle_reasons,
+            cpu_w: s.cpu_w,
+            cpu_w_raw: s.cpu_w_raw,
+            per_socket_w: &s.per_socket_w,
+            per_core_usage: None,
+            per_core_w: None,
+            idle_gpu_w: s.idle_gpu_w,
+            idle_cpu_w: s.idle_cpu_w,
+            wall_w: s.wall_w,
+            idle_wall_w: s.idle_wall_w,
+            dram_w: s.dram_w,
+            idle_dram_w: s.idle_dram_w,
+            model_error_w: s.model_error_w,
+            compute_net_w: s.compute_net_w,
+            metered_w: s.metered_w,
+            meter_mode: s.meter_mode,
+            network_w: s.network_w,
+            agent_self_w: s.agent_self_w,
+            bucket_j: s.bucket_j,
+            bucket_wh: s.bucket_j / J_PER_WH,
+            buckets: &s.buckets,
+            spilled_j: s.spilled_j,
+            total_integrated_j: s.total_integrated_j,
+            cpu_source: s.cpu_source,
+            cgroup_aware: s.cgroup_aware,
+            power_sources: &s.power_sources,
+            power_quality: s.power_quality,
+            warmup: s.warmup,
+            paused: s.paused,
+            warming_up: s.warming_up,
+            warmup_remaining_s: s.warmup_remaining_s,
+            active_duty_pct: s.active_duty_pct,
+            active_s: s.active_s,
+            idle_s: s.idle_s,
+            extremes: s.extremes,
+            suspend_gaps: s.suspend_gaps,
+            period_s: s.period_s,
+            actual_dt_s: s.actual_dt_s,
+            battery_w: s.battery_w,
+            on_ac: s.on_ac,
+            refill_in_s: s.refill_in_s,
+            cost_usd: s.cost_usd,
+            co2_g: s.co2_g,
+            cost_rate_usd_per_h: s.cost_rate_usd_per_h,
+            co2_g_per_kwh: s.co2_g_per_kwh,
+            stats: &s.stats,
+            hash: &s.hash,
+            prev_hash: &s.prev_hash,
+            hash_algo: s.hash_algo,
+            sig: s.sig.as_deref(),
+        }
+    }
+}
+
+// rescale the joule fields of a sample payload in place. The signature covers the joule values,
+// so it is dropped rather than left unverifiable
+fn convert_energy(v: &mut serde_json::Value, unit: EnergyUnit) {
+    let Some(obj) = v.as_object_mut() else { return };
+    let per = unit.joules_per_unit();
+    for key in ["bucket_j", "cap_j", "spilled_j", "total_integrated_j"] {
+        if let Some(j) = obj.get(key).and_then(|j| j.as_f64()) { obj.insert(key.into(), serde_json::json!(j / per)); }
+    }
+    if let Some(buckets) = obj.get_mut("buckets").and_then(|b| b.as_object_mut()) {
+        for j in buckets.values_mut() {
+            if let Some(x) = j.as_f64() { *j = serde_json::json!(x / per); }
+        }
+    }
+    obj.remove("sig");
+    obj.insert("units".into(), serde_json::json!(unit));
+}
+
+fn sample_json(s: &Last) -> serde_json::Value {
+    serde_json::to_value(SampleResponse::from(s)).unwrap_or_default()
+}
+
+// what /v1/history's since and /v1/export's from/to are matched against: with JOULE_CLOCK=both,
+// the monotonic clock
+fn history_key(s: &Last) -> f64 { s.mono_ts.unwrap_or(s.ts) }
+
+// where a /v1/export stream has got to: the next history sequence for samples, the last period
+// start sent for aggregates
+struct ExportCursor { seq: u64, after_ts: f64 }
+
+// the original /v1/sample payload, served with api_version v1
+#[derive(Serialize)]
+struct LegacySample { ts: f64, gpu_w: f64, cpu_w: f64, idle_gpu_w: f64, idle_cpu_w: f64, net_w: f64, bucket_j: f64 }
+
+impl From<&Last> for LegacySample {
+    fn from(s: &Last) -> Self {
+        LegacySample {
+            ts: s.ts, gpu_w: s.gpu_w, cpu_w: s.cpu_w, idle_gpu_w: s.idle_gpu_w, idle_cpu_w: s.idle_cpu_w,
+            net_w: s.compute_net_w, bucket_j: s.bucket_j,
+        }
+    }
+}
+
+fn versioned_json(s: &Last, version: ApiVersion) -> serde_json::Value {
+    match version {
+        ApiVersion::V1 => serde_json::to_value(LegacySample::from(s)).unwrap_or_default(),
+        ApiVersion::V2 => sample_json(s),
+    }
+}
+
+// ?api_version= wins over the X-Api-Version header, which wins over JOULE_API_VERSION
+fn api_version(st: &State, headers: &HeaderMap, query: Option<ApiVersion>) -> ApiVersion {
+    let header = headers.get("x-api-version").and_then(|v| v.to_str().ok()).and_then(|v| match v.trim() {
+        "v1" | "1" => Some(ApiVersion::V1),
+        "v2" | "2" => Some(ApiVersion::V2),
+        _ => None,
+    });
+    query.or(header).unwrap_or(st.cfg.api_version)
+}
+
+// everything /metrics serves, shared with the remote-write push so both report the same series
+fn scrape(st: &State) -> (Vec<metrics::Metric>, metrics::Histogram) {
+    let mut all = power_gauges(&st.last.lock());
+    all.extend(st.extremes.lock().gauges());
+    all.push(metrics::Metric::counter("cryoflux_integrated_joules_total", "Lifetime integrated joules; takes and refunds do not change it", *st.total_integrated_j.lock()));
+    all.push(metrics::Metric::counter("cryoflux_take_total", "Granted takes", st.take_granted.load(Ordering::Relaxed) as f64));
+    all.push(metrics::Metric::counter("cryoflux_take_denied_total", "Denied takes", st.take_denied.load(Ordering::Relaxed) as f64));
+    let hist = st.take_hist.lock();
+    let take_hist = metrics::Histogram {
+        name: "cryoflux_take_joules",
+        help: "Joules per granted take",
+        buckets: hist.cumulative().iter().map(|b| (b.le, b.count)).collect(),
+        sum: hist.sum(),
+        count: hist.count(),
+    };
+    (all, take_hist)
+}
+
+// the sample's gauges, shared by /metrics and the OTLP exporter so both report the same series
+fn power_gauges(s: &Last) -> Vec<metrics::Metric> {
+    vec![
+        metrics::Metric::gauge("cryoflux_gpu_watts", "GPU power draw in watts", s.gpu_w),
+        metrics::Metric::gauge("cryoflux_cpu_watts", "CPU power draw in watts", s.cpu_w),
+        metrics::Metric::gauge("cryoflux_net_watts", "Compute power above idle in watts", s.compute_net_w),
+        metrics::Metric::gauge("cryoflux_agent_self_watts", "The agent's own share of CPU power above idle in watts", s.agent_self_w),
+        metrics::Metric::gauge("cryoflux_idle_gpu_watts", "Learned GPU idle baseline in watts", s.idle_gpu_w),
+        metrics::Metric::gauge("cryoflux_idle_cpu_watts", "Learned CPU idle baseline in watts", s.idle_cpu_w),
+        metrics::Metric::gauge("cryoflux_bucket_joules", "Spendable joules in the bucket", s.bucket_j),
+        metrics::Metric::gauge("cryoflux_power_quality", "Power reading quality: 2 measured, 1 estimated, 0 unavailable", s.power_quality.level()),
+    ]
+}
+
+// None for no values, so missing data reads as null rather than a zero that looks real
+fn sum(values: impl Iterator<Item = f64>) -> Option<f64> {
+    values.fold(None, |acc, v| Some(acc.unwrap_or(0.0) + v))
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (total, n) = values.fold((0.0, 0usize), |(t, n), v| (t + v, n + 1));
+    (n > 0).then(|| total / n as f64)
+}
+
+// accepts IPv4 and IPv6, with or without brackets ("::1", "[::1]")
+fn parse_bind_addr(raw: &str) -> Result<IpAddr, String> {
+    let trimmed = raw.trim();
+    let bare = trimmed.strip_prefix('[').and_then(|r| r.strip_suffix(']')).unwrap_or(trimmed);
+    bare.parse().map_err(|e| format!("invalid JOULE_BIND_ADDR {:?}: {}", raw, e))
+}
+
+// (hostname, machine_id) attached to every sample and metric
+fn identity() -> (Arc<str>, Arc<str>) {
+    let host = System::host_name().unwrap_or_else(|| "unknown".into());
+    // /etc/machine-id is systemd's, the dbus copy covers older distros; elsewhere fall back to the hostname
+    let machine_id = config::lookup("JOULE_NODE_ID")
+        .or_else(|| ["/etc/machine-id", "/var/lib/dbus/machine-id"].iter()
+            .find_map(|p| std::fs::read_to_string(p).ok().map(|s| s.trim().to_string()).filter(|s| !s.is_empty())))
+        .unwrap_or_else(|| host.clone());
+    (host.into(), machine_id.into())
+}
+
+// distinct from or_exit's 1 so supervisors can tell "port busy" from bad configuration
+const EXIT_BIND_FAILED: i32 = 2;
+
+async fn bind_with_retry(addr: SocketAddr, attempts: u32, delay: Duration) -> std::io::Result<tokio::net::TcpListener> {
+    let mut attempt = 1;
+    loop {
+        match bind_reuse(addr) {
+            Ok(l) => return Ok(l),
+            Err(e) if attempt >= attempts => return Err(e),
+            Err(e) => tracing::warn!(%addr, error = %e, attempt, attempts, "bind failed, retrying"),
+        }
+        attempt += 1;
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// SO_REUSEADDR lets a quick restart bind while the old socket sits in TIME_WAIT. Not on Windows,
+// where it would let two processes bind the same port
+fn bind_reuse(addr: SocketAddr) -> std::io::Result<tokio::net::TcpListener> {
+    let socket = if addr.is_ipv4() { tokio::net::TcpSocket::new_v4()? } else { tokio::net::TcpSocket::new_v6()? };
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+// startup misconfiguration: log a clear message and exit instead of panicking
+fn or_exit<T>(r: Result<T, String>) -> T {
+    r.unwrap_or_else(|e| {
+        tracing::error!("{}", e);
+        std::process::exit(1)
+    })
+}
+
+fn default_bucket() -> String { DEFAULT_BUCKET.to_string() }
+
+// quota mode (JOULE_REFILL_J every JOULE_REFILL_INTERVAL_S); None when neither is set
+fn parse_refill() -> Result<Option<Refill>, String> {
+    let (joules, interval_s) = match (env_opt_f("JOULE_REFILL_J"), env_opt_f("JOULE_REFILL_INTERVAL_S")) {
+        (None, None) => return Ok(None),
+        (Some(j), Some(i)) => (j, i),
+        _ => return Err("JOULE_REFILL_J and JOULE_REFILL_INTERVAL_S must be set together".into()),
+    };
+    if !(joules.is_finite() && joules >= 0.0) { return Err("JOULE_REFILL_J must be >= 0".into()); }
+    if !(interval_s.is_finite() && interval_s > 0.0) { return Err("JOULE_REFILL_INTERVAL_S must be > 0".into()); }
+    let mode = match config::lookup("JOULE_REFILL_MODE").as_deref() {
+        Some("add") | None => RefillMode::Add,
+        Some("set") => RefillMode::Set,
+        Some(other) => return Err(format!("unknown JOULE_REFILL_MODE {:?} (add|set)", other)),
+    };
+    Ok(Some(Refill { joules, interval_s, mode }))
+}
+
+// "gpu:3,cpu:1" -> [("gpu", 0.75), ("cpu", 0.25)]
+fn parse_weights(raw: &str) -> Vec<(String, f64)> {
+    let mut out = Vec::new();
+    for item in raw.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+        match item.split_once(':').map(|(n, w)| (n.trim(), w.trim().parse::<f64>())) {
+            Some((name, Ok(w))) if !name.is_empty() && w.is_finite() && w > 0.0 => out.push((name.to_string(), w)),
+            _ => tracing::warn!(item, "ignoring invalid bucket weight"),
+        }
+    }
+    let total: f64 = out.iter().map(|(_, w)| w).sum();
+    for (_, w) in out.iter_mut() { *w /= total; }
+    out
+}
+
+// the live-tunable settings, at startup and again on SIGHUP; also the per-socket TDPs, since
+// cpu_tdp_w is their sum
+fn load_tunables() -> Result<(Tunables, Vec<f64>), String> {
+    let socket_tdp_w = parse_socket_tdp(&config::lookup("JOULE_CPU_TDP_W").unwrap_or_else(|| "65".into()))?;
+    let tune = Tunables {
+        cpu_tdp_w: socket_tdp_w.iter().sum(),
+        smoothing_alpha: env_f("JOULE_SMOOTHING", 0.2),
+        power_smoothing: env_f("JOULE_POWER_SMOOTHING", 1.0),
+        hz: env_opt_f("JOULE_INTEGRATE_HZ").unwrap_or_else(|| env_f("JOULE_HZ", 1.0)),
+        publish_hz: env_opt_f("JOULE_PUBLISH_HZ"),
+        idle_learn_w: env_f("JOULE_IDLE_LEARN_W", 5.0),
+        idle_learn_frac: env_f("JOULE_IDLE_LEARN_FRAC", 0.1),
+        idle_learn_mode: match config::lookup("JOULE_IDLE_LEARN_MODE").as_deref() {
+            Some("absolute") | None => IdleLearnMode::Absolute,
+            Some("fraction") => IdleLearnMode::Fraction,
+            Some("both") => IdleLearnMode::Both,
+            Some(other) => {
+                tracing::warn!(value = other, "unknown JOULE_IDLE_LEARN_MODE, using absolute");
+                IdleLearnMode::Absolute
+            }
+        },
+    };
+    tune.validate()?;
+    Ok((tune, socket_tdp_w))
+}
+
+fn load_cpu_curve() -> Result<Option<Vec<(f64, f64)>>, String> {
+    config::lookup("JOULE_CPU_CURVE").map(|raw| parse_cpu_curve(&raw)).transpose()
+}
+
+// "65" or one TDP per socket, "125,125"
+fn parse_socket_tdp(raw: &str) -> Result<Vec<f64>, String> {
+    let tdp: Vec<f64> = raw.split(',').map(|w| w.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid JOULE_CPU_TDP_W {:?} (watts, or one per socket: 125,125)", raw))?;
+    if tdp.iter().any(|w| !(w.is_finite() && *w > 0.0)) { return Err("JOULE_CPU_TDP_W must be > 0".into()); }
+    Ok(tdp)
+}
+
+// JOULE_IDLE_<side>_MIN_W / _MAX_W, else the shared JOULE_IDLE_MIN_W / JOULE_IDLE_MAX_W
+fn parse_idle_bounds(side: &str) -> Result<IdleBounds, String> {
+    let get = |bound: &str| env_opt_f(&format!("JOULE_IDLE_{}_{}_W", side, bound)).or_else(|| env_opt_f(&format!("JOULE_IDLE_{}_W", bound)));
+    let (min_w, max_w) = (get("MIN").unwrap_or(0.0), get("MAX").unwrap_or(f64::INFINITY));
+    if !(min_w >= 0.0 && min_w <= max_w) {
+        return Err(format!("JOULE_IDLE_{}_MIN_W/MAX_W: need 0 <= min <= max, got {}..{}", side, min_w, max_w));
+    }
+    Ok(IdleBounds { min_w, max_w })
+}
+
+// keep the per-socket idle baselines summing to the combined one, which calibrate, reset and a
+// restored state file set directly: rescale them, or split evenly when the socket count changed
+fn rebase_sockets(idle: &mut Vec<f64>, combined: f64, sockets: usize) {
+    let sum: f64 = idle.iter().sum();
+    if idle.len() == sockets.max(1) && (sum - combined).abs() <= 1e-9 * combined.abs().max(1.0) { return; }
+    if idle.len() == sockets.max(1) && sum > 0.0 {
+        for i in idle.iter_mut() { *i *= combined / sum; }
+    } else {
+        *idle = vec![combined / sockets.max(1) as f64; sockets.max(1)];
+    }
+}
+
+// util:watts breakpoints, e.g. "0:10,25:35,50:55,100:95"; utilization strictly increasing from 0 to 100
+fn parse_cpu_curve(raw: &str) -> Result<Vec<(f64, f64)>, String> {
+    let mut points: Vec<(f64, f64)> = Vec::new();
+    for item in raw.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+        let point = item.split_once(':').and_then(|(u, w)| Some((u.trim().parse::<f64>().ok()?, w.trim().parse::<f64>().ok()?)));
+        match point {
+            Some((u, w)) if u.is_finite() && w.is_finite() && w >= 0.0 => {
+                if points.last().is_some_and(|&(prev, _)| u <= prev) {
+                    return Err(format!("JOULE_CPU_CURVE utilization must increase: {:?}", item));
+                }
+                points.push((u, w));
+            }
+            _ => return Err(format!("invalid JOULE_CPU_CURVE point {:?} (util:watts)", item)),
+        }
+    }
+    if points.first().map(|p| p.0) != Some(0.0) || points.last().map(|p| p.0) != Some(100.0) {
+        return Err("JOULE_CPU_CURVE must cover utilization 0 and 100".into());
+    }
+    Ok(points)
+}
+
+// piecewise-linear between breakpoints; `curve` is sorted and spans 0..=100
+fn interpolate(curve: &[(f64, f64)], util: f64) -> f64 {
+    let util = util.clamp(0.0, 100.0);
+    let i = curve.partition_point(|&(u, _)| u < util).clamp(1, curve.len() - 1);
+    let ((u0, w0), (u1, w1)) = (curve[i - 1], curve[i]);
+    w0 + (w1 - w0) * (util - u0) / (u1 - u0)
+}
+
+// seconds since the previous tick as measured, not the nominal period, so a tick that fires late
+// (a stalled runtime, a slow source) still integrates the whole gap; the first tick counts as one
+// period. The measured interval is returned too, for /v1/diag
+fn tick_dt(prev: &mut Option<Instant>, now: Instant, period: Duration) -> (Option<Duration>, f64) {
+    let interval = prev.replace(now).map(|p| now - p);
+    (interval, interval.unwrap_or(period).as_secs_f64())
+}
+
+// joules over one tick by the trapezoidal rule, so ramps are neither over- nor under-counted; the
+// first tick has no previous reading and falls back to the rectangle
+fn step_joules(prev_w: Option<f64>, now_w: f64, dt: f64) -> f64 {
+    match prev_w {
+        Some(p) => (p + now_w) / 2.0 * dt,
+        None => now_w * dt,
+    }
+}
+
+// one EMA step per entry; a changed entry count (a device appearing, replay starting) restarts
+// from the reading
+fn smooth(ema: &mut Vec<f64>, raw: &[f64], alpha: f64) -> Vec<f64> {
+    if ema.len() != raw.len() { ema.clear(); ema.extend_from_slice(raw); }
+    for (e, r) in ema.iter_mut().zip(raw) { *e = alpha * r + (1.0 - alpha) * *e; }
+    ema.clone()
+}
+
+// cpu_w attributed to cores by their share of total usage; an all-idle CPU splits evenly
+fn split_by_usage(cpu_w: f64, usage: &[f32]) -> Vec<f64> {
+    let total: f64 = usage.iter().map(|&u| u as f64).sum();
+    usage
+        .iter()
+        .map(|&u| if total > 0.0 { cpu_w * u as f64 / total } else { cpu_w / usage.len() as f64 })
+        .collect()
+}
+
 fn avg_cpu_usage(sys: &System) -> f32 {
     let cpus = sys.cpus(); if cpus.is_empty() { return 20.0; }
     let mut s = 0.0; for c in cpus { s += c.cpu_usage(); } s / (cpus.len() as f32)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every setting at its default: the tests set no JOULE_* variables
+    fn test_state() -> State {
+        let (tune, socket_tdp_w) = load_tunables().expect("default tunables");
+        State::new(load_cfg(socket_tdp_w), tune, None, None, "test".into(), "test".into(), watch::channel(false).1)
+    }
+
+    // a tick firing at 3x the period integrates the 3 s that actually passed, not the nominal 1 s
+    #[test]
+    fn stalled_tick_integrates_real_interval() {
+        let period = Duration::from_secs(1);
+        let watts = 120.0;
+        let t0 = Instant::now();
+        let mut prev = None;
+        let (interval, dt) = tick_dt(&mut prev, t0, period);
+        assert!(interval.is_none());
+        assert_eq!(dt, 1.0);
+        let (interval, dt) = tick_dt(&mut prev, t0 + period * 3, period);
+        assert_eq!(interval, Some(period * 3));
+        let joules = step_joules(Some(watts), watts, dt);
+        assert!((joules - watts * 3.0).abs() < 1e-9, "{} J over a 3 s stall at {} W", joules, watts);
+        // and the tick after it is back to one period
+        let (_, dt) = tick_dt(&mut prev, t0 + period * 4, period);
+        assert!((step_joules(Some(watts), watts, dt) - watts).abs() < 1e-9);
+    }
+
+    // on a linear ramp P(t) = a + b*t the trapezoid is exact; the rectangle (each tick at its
+    // closing reading) over-counts by b*dt*T/2
+    #[test]
+    fn trapezoid_beats_rectangle_on_ramp() {
+        let (a, b) = (40.0, 2.5);
+        let (dt, steps) = (0.5, 120);
+        let p = |t: f64| a + b * t;
+        let (mut trapezoid, mut rectangle) = (0.0, 0.0);
+        let mut prev = p(0.0);
+        for i in 1..=steps {
+            let w = p(i as f64 * dt);
+            trapezoid += step_joules(Some(prev), w, dt);
+            rectangle += step_joules(None, w, dt);
+            prev = w;
+        }
+        let t = steps as f64 * dt;
+        let exact = a * t + b * t * t / 2.0;
+        assert!((trapezoid - exact).abs() < 1e-9, "trapezoid {} vs {}", trapezoid, exact);
+        assert!((rectangle - exact).abs() > (trapezoid - exact).abs());
+        assert!((rectangle - exact - b * dt * t / 2.0).abs() < 1e-9);
+    }
+
+    // zero is a valid take (it just reports the balance); anything negative or non-finite is not
+    #[test]
+    fn check_joules_rejects_bad_amounts() {
+        assert!(check_joules(0.0).is_ok());
+        assert!(check_joules(12.5).is_ok());
+        for bad in [-1.0, -f64::MIN_POSITIVE, f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let e = check_joules(bad).expect_err(&format!("{} accepted", bad));
+            assert_eq!(e.status, StatusCode::BAD_REQUEST, "{}", bad);
+            assert_eq!(e.code, "invalid_input", "{}", bad);
+        }
+    }
+
+    // an empty batch or one of only 0 J items is a no-op, like a 0 J take: no counters, histogram
+    // or /v1/takes entries
+    #[test]
+    fn zero_batch_counts_nothing() {
+        let st = test_state();
+        let item = |bucket: &str, joules| TakeReq { joules, bucket: bucket.into(), allow_partial: false, wait_ms: None };
+        for items in [vec![], vec![item(DEFAULT_BUCKET, 0.0), item("other", 0.0)]] {
+            let Ok(remaining) = st.take_batch(&items) else { panic!("zero batch denied") };
+            assert_eq!(remaining.len(), items.len());
+        }
+        assert_eq!(st.take_granted.load(Ordering::Relaxed), 0);
+        assert_eq!(st.take_denied.load(Ordering::Relaxed), 0);
+        assert_eq!(st.take_hist.lock().summary().count, 0);
+        assert!(st.take_log.lock().recent(10).is_empty());
+
+        st.integrate(&mut st.buckets.lock(), 10.0);
+        assert!(st.take_batch(&[item(DEFAULT_BUCKET, 1.0), item(DEFAULT_BUCKET, 0.0)]).is_ok());
+        assert_eq!(st.take_granted.load(Ordering::Relaxed), 1);
+        assert_eq!(st.take_hist.lock().summary().count, 1);
+        assert_eq!(st.take_log.lock().recent(10).len(), 2);
+    }
+
+    // a PUT /v1/config that leaves publish_hz out keeps it; an explicit null resets it to every tick
+    #[test]
+    fn publish_hz_patch_tells_null_from_absent() {
+        let tune = Tunables { publish_hz: Some(2.0), ..load_tunables().expect("default tunables").0 };
+        let patch = |body: &str| serde_json::from_str::<TunablesPatch>(body).unwrap();
+        assert_eq!(tune.apply(&patch(r#"{"hz": 20}"#)).publish_hz, Some(2.0));
+        assert_eq!(tune.apply(&patch(r#"{"publish_hz": null}"#)).publish_hz, None);
+        assert_eq!(tune.apply(&patch(r#"{"publish_hz": 5}"#)).publish_hz, Some(5.0));
+    }
+
+    // malformed JSON and wrongly typed fields both come back as the 400 envelope, not axum's plain text
+    #[tokio::test]
+    async fn bad_json_body_gets_error_envelope() {
+        for body in [r#"{"joules": 1"#, r#"{"joules": "lots"}"#] {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("/v1/take")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(body))
+                .unwrap();
+            let Err(e) = ApiJson::<TakeReq>::from_request(req, &()).await else { panic!("{} parsed", body) };
+            let resp = e.into_response();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            let v: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(v["error"]["code"], "invalid_input", "{}", body);
+            assert!(v["error"]["message"].as_str().is_some_and(|m| !m.is_empty()));
+        }
+    }
+
+    // only the configured token earns its own budget; made-up ones fall back to the peer address
+    #[test]
+    fn rate_client_ignores_unchecked_tokens() {
+        let peer = SocketAddr::from(([192, 0, 2, 7], 4000));
+        let with = |token: &str| {
+            let mut h = HeaderMap::new();
+            h.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+            h
+        };
+        let mut st = test_state();
+        assert!(rate_client(&st, &with("fake-1"), peer) == ratelimit::Client::Ip(peer.ip()));
+        st.cfg.api_token = Some("secret".into());
+        assert!(rate_client(&st, &with("fake-2"), peer) == ratelimit::Client::Ip(peer.ip()));
+        assert!(matches!(rate_client(&st, &with("secret"), peer), ratelimit::Client::Token(_)));
+        assert!(rate_client(&st, &HeaderMap::new(), peer) == ratelimit::Client::Ip(peer.ip()));
+    }
+
+    // takes racing the sampler's credits: nothing is lost or minted, and no balance dips below zero
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_takes_during_integration() {
+        const TAKERS: usize = 8;
+        const TAKES: usize = 500;
+        const STEPS: usize = 2000;
+        const STEP_J: f64 = 1.5;
+        let st = test_state();
+        let takers: Vec<_> = (0..TAKERS).map(|_| {
+            let st = st.clone();
+            tokio::spawn(async move {
+                let mut granted = 0.0;
+                for _ in 0..TAKES {
+                    let r = st.take(DEFAULT_BUCKET, 1.0, true);
+                    assert!(r.remaining_j >= 0.0, "balance went negative: {}", r.remaining_j);
+                    granted += r.granted_j.unwrap_or(0.0);
+                    tokio::task::yield_now().await;
+                }
+                granted
+            })
+        }).collect();
+        let sampler = {
+            let st = st.clone();
+            tokio::spawn(async move {
+                for _ in 0..STEPS {
+                    {
+                        let mut b = st.buckets.lock();
+                        st.integrate(&mut b, STEP_J);
+                        assert!(b.values().all(|&j| j >= 0.0));
+                    }
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        sampler.await.unwrap();
+        let mut granted = 0.0;
+        for t in takers { granted += t.await.unwrap(); }
+        let remaining = st.buckets.lock()[DEFAULT_BUCKET];
+        let credited = STEPS as f64 * STEP_J;
+        assert!(remaining >= 0.0);
+        assert!(granted > 0.0, "no take ever succeeded");
+        assert!((granted + remaining - credited).abs() < 1e-6, "granted {} + remaining {} != credited {}", granted, remaining, credited);
+    }
+}
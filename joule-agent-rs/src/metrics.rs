@@ -0,0 +1,72 @@
+// Prometheus text exposition format (version 0.0.4)
+use std::fmt::Write;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(Clone, Copy)]
+pub enum Kind { Gauge, Counter }
+
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+    pub value: f64,
+}
+
+impl Metric {
+    pub fn gauge(name: &'static str, help: &'static str, value: f64) -> Self {
+        Metric { name, help, kind: Kind::Gauge, value }
+    }
+    pub fn counter(name: &'static str, help: &'static str, value: f64) -> Self {
+        Metric { name, help, kind: Kind::Counter, value }
+    }
+}
+
+// cumulative `le` counts, the last bucket (None) being +Inf
+pub struct Histogram {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub buckets: Vec<(Option<f64>, u64)>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+// label values escape backslash, double quote and newline
+fn label_set(labels: &[(&str, &str)], extra: Option<(&str, &str)>) -> String {
+    let pairs: Vec<String> = labels.iter().chain(&extra)
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect();
+    if pairs.is_empty() { String::new() } else { format!("{{{}}}", pairs.join(",")) }
+}
+
+// `labels` (host identity) are attached to every series
+pub fn render(metrics: &[Metric], labels: &[(&str, &str)]) -> String {
+    let mut out = String::new();
+    let set = label_set(labels, None);
+    for m in metrics {
+        let kind = match m.kind { Kind::Gauge => "gauge", Kind::Counter => "counter" };
+        let _ = writeln!(out, "# HELP {} {}", m.name, m.help);
+        let _ = writeln!(out, "# TYPE {} {}", m.name, kind);
+        let _ = writeln!(out, "{}{} {}", m.name, set, m.value);
+    }
+    out
+}
+
+// the `le` label value of a bucket bound
+pub fn le(bound: Option<f64>) -> String {
+    bound.map_or_else(|| "+Inf".to_string(), |b| b.to_string())
+}
+
+// cumulative `le` buckets (the last one +Inf) followed by _sum and _count
+pub fn render_histogram(h: &Histogram, labels: &[(&str, &str)]) -> String {
+    let mut out = String::new();
+    let set = label_set(labels, None);
+    let _ = writeln!(out, "# HELP {} {}", h.name, h.help);
+    let _ = writeln!(out, "# TYPE {} histogram", h.name);
+    for (bound, n) in &h.buckets {
+        let _ = writeln!(out, "{}_bucket{} {}", h.name, label_set(labels, Some(("le", &le(*bound)))), n);
+    }
+    let _ = writeln!(out, "{}_sum{} {}", h.name, set, h.sum);
+    let _ = writeln!(out, "{}_count{} {}", h.name, set, h.count);
+    out
+}
@@ -0,0 +1,87 @@
+// MQTT sample publisher (JOULE_MQTT_URL + JOULE_MQTT_TOPIC, cargo feature "mqtt")
+use crate::{shutdown_requested, versioned_json, State};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+
+pub struct MqttCfg {
+    pub url: String,
+    pub topic: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub interval_s: Option<f64>,
+}
+
+// mqtt://host[:port] or mqtts://; rumqttc wants the client id in the query string, so add one if missing
+pub fn options(cfg: &MqttCfg) -> Result<MqttOptions, String> {
+    let mut url = cfg.url.clone();
+    if !url.contains("client_id=") {
+        let id = format!("client_id=cryoflux-{}", std::process::id());
+        url.push(if url.contains('?') { '&' } else { '?' });
+        url.push_str(&id);
+    }
+    let mut opts = MqttOptions::parse_url(url).map_err(|e| format!("invalid JOULE_MQTT_URL: {}", e))?;
+    opts.set_keep_alive(Duration::from_secs(30));
+    // consumers learn the agent died from the broker, not from us
+    opts.set_last_will(LastWill::new(status_topic(&cfg.topic), "offline", QoS::AtLeastOnce, true));
+    if let Some(user) = &cfg.username {
+        opts.set_credentials(user, cfg.password.clone().unwrap_or_default());
+    }
+    Ok(opts)
+}
+
+fn status_topic(topic: &str) -> String { format!("{}/status", topic.trim_end_matches('/')) }
+
+pub async fn run(st: State, cfg: MqttCfg, opts: MqttOptions) {
+    let (client, mut events) = AsyncClient::new(opts, 64);
+    let status = status_topic(&cfg.topic);
+
+    // the event loop drives the connection; polling again after an error reconnects
+    let st_ev = st.clone();
+    let (client_ev, status_ev) = (client.clone(), status.clone());
+    let driver = tokio::spawn(async move {
+        loop {
+            match events.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    tracing::info!("MQTT connected");
+                    // retained so late subscribers see it; re-sent on every reconnect
+                    let _ = client_ev.try_publish(&status_ev, QoS::AtLeastOnce, true, "online");
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                Ok(_) => {}
+                Err(_) if *st_ev.shutdown.borrow() => break,
+                Err(e) => {
+                    tracing::warn!(error = %e, "MQTT connection lost, reconnecting");
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                }
+            }
+        }
+    });
+
+    let min_gap = cfg.interval_s.map(|s| Duration::from_secs_f64(s.max(0.0)));
+    let mut last_sent: Option<Instant> = None;
+    let mut rx = st.samples_tx.subscribe();
+    loop {
+        tokio::select! {
+            r = rx.recv() => match r {
+                Ok(s) => {
+                    if min_gap.is_some_and(|gap| last_sent.is_some_and(|t| t.elapsed() < gap)) { continue; }
+                    last_sent = Some(Instant::now());
+                    // try_publish: while the broker is away, drop samples instead of queueing them
+                    let payload = versioned_json(&s, st.cfg.api_version).to_string();
+                    if client.try_publish(&cfg.topic, QoS::AtMostOnce, false, payload).is_err() {
+                        tracing::debug!("MQTT queue full, sample dropped");
+                    }
+                }
+                Err(RecvError::Lagged(n)) => tracing::warn!(missed = n, "MQTT publisher fell behind"),
+                Err(RecvError::Closed) => break,
+            },
+            _ = shutdown_requested(st.shutdown.clone()) => break,
+        }
+    }
+
+    // a clean stop doesn't trigger the last will, so say goodbye explicitly
+    let _ = client.try_publish(&status, QoS::AtLeastOnce, true, "offline");
+    let _ = client.try_disconnect();
+    let _ = tokio::time::timeout(Duration::from_secs(2), driver).await;
+}
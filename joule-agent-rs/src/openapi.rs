@@ -0,0 +1,207 @@
+// OpenAPI 3 document (GET /openapi.json) and the Swagger UI page (GET /docs, JOULE_DOCS=1). The
+// handlers are closures in main.rs, so the routes are listed here by hand; the schemas come from
+// the ToSchema derives on the request/response types
+use crate::{
+    AdmitReq, ApiVersion, BucketResp, CalibrateResp, CanTakeResp, EnergyUnit, IdleLearnMode, PowerCapReq, RefundReq, RefundResp,
+    ReserveReq, ReserveResp, SampleResponse, SettleResp, TakeBatchReq, TakeBatchResp, TakeReq, TakeResp, TunablesPatch,
+};
+use crate::{admit, diag, powercap, rollup, takehist, takelog};
+use utoipa::openapi::{
+    path::{HttpMethod, OperationBuilder, ParameterBuilder, ParameterIn},
+    request_body::RequestBodyBuilder,
+    schema::{ArrayBuilder, ObjectBuilder, Schema, Type},
+    ContentBuilder, InfoBuilder, OpenApi, Ref, RefOr, Required, ResponseBuilder,
+};
+use utoipa::OpenApi as _;
+
+#[derive(utoipa::OpenApi)]
+#[openapi(components(schemas(
+    SampleResponse, TakeReq, TakeResp, TakeBatchReq, TakeBatchResp, AdmitReq, admit::Admission, RefundReq, RefundResp,
+    ReserveReq, ReserveResp, SettleResp, BucketResp, CanTakeResp, CalibrateResp, PowerCapReq, powercap::DeviceCap,
+    rollup::Period, diag::Report, takelog::Entry, takehist::Summary, ApiVersion, EnergyUnit, TunablesPatch, IdleLearnMode,
+)))]
+struct Components;
+
+// a JSON body: a component by name, or an inline schema
+enum Body { Named(&'static str), List(&'static str), Object(&'static str) }
+
+impl Body {
+    fn schema(&self) -> RefOr<Schema> {
+        match *self {
+            Body::Named(name) => Ref::from_schema_name(name).into(),
+            Body::List(name) => ArrayBuilder::new().items(Ref::from_schema_name(name)).into(),
+            Body::Object(desc) => ObjectBuilder::new().description(Some(desc)).into(),
+        }
+    }
+}
+
+struct Op { method: HttpMethod, path: &'static str, summary: &'static str, op: OperationBuilder }
+
+impl Op {
+    fn new(method: HttpMethod, path: &'static str, summary: &'static str) -> Op {
+        Op { method, path, summary, op: OperationBuilder::new() }
+    }
+
+    fn query(mut self, name: &str, schema: impl Into<RefOr<Schema>>, desc: &str) -> Op {
+        self.op = self.op.parameter(ParameterBuilder::new().name(name).parameter_in(ParameterIn::Query)
+            .required(Required::False).schema(Some(schema)).description(Some(desc)));
+        self
+    }
+
+    fn id(mut self) -> Op {
+        self.op = self.op.parameter(ParameterBuilder::new().name("id").parameter_in(ParameterIn::Path)
+            .required(Required::True).schema(Some(ObjectBuilder::new().schema_type(Type::Integer))));
+        self
+    }
+
+    fn api_version(self) -> Op {
+        self.query("api_version", Ref::from_schema_name("ApiVersion"), "Sample schema; defaults to JOULE_API_VERSION or the X-Api-Version header")
+    }
+
+    fn units(self) -> Op {
+        self.query("units", Ref::from_schema_name("EnergyUnit"), "Unit of the energy fields (default j)")
+    }
+
+    fn body(mut self, name: &'static str) -> Op {
+        let content = ContentBuilder::new().schema(Some(Body::Named(name).schema())).build();
+        self.op = self.op.request_body(Some(RequestBodyBuilder::new().content("application/json", content).required(Some(Required::True)).build()));
+        self
+    }
+
+    fn ok(mut self, body: Body) -> Op {
+        let content = ContentBuilder::new().schema(Some(body.schema())).build();
+        self.op = self.op.response("200", ResponseBuilder::new().description("OK").content("application/json", content));
+        self
+    }
+
+    fn ok_as(mut self, content_type: &str, desc: &str) -> Op {
+        self.op = self.op.response("200", ResponseBuilder::new().description(desc).content(content_type, ContentBuilder::new().build()));
+        self
+    }
+
+    fn errors(mut self, codes: &[u16]) -> Op {
+        for &code in codes {
+            let content = ContentBuilder::new().schema(Some(Ref::from_schema_name("Error"))).build();
+            self.op = self.op.response(code.to_string(), ResponseBuilder::new().description(error_desc(code)).content("application/json", content));
+        }
+        self
+    }
+}
+
+fn error_desc(code: u16) -> &'static str {
+    match code {
+        400 => "invalid_input: a malformed body or an out-of-range value",
+        401 => "unauthorized: missing or invalid bearer token (JOULE_API_TOKEN)",
+        402 => "insufficient_balance: the bucket holds less than requested",
+        403 => "forbidden",
+        404 => "not_found",
+        415 => "unsupported_media_type: the body must be application/json",
+        429 => "rate_limited: retry after retry_after_s (JOULE_TAKE_RPS)",
+        501 => "not_supported: the GPU has no power limit",
+        503 => "unavailable, or warming_up during JOULE_WARMUP_S (retry after retry_after_s)",
+        _ => "error",
+    }
+}
+
+fn number() -> ObjectBuilder { ObjectBuilder::new().schema_type(Type::Number) }
+fn integer() -> ObjectBuilder { ObjectBuilder::new().schema_type(Type::Integer) }
+fn string() -> ObjectBuilder { ObjectBuilder::new().schema_type(Type::String) }
+
+// every error response: {"error": {"code", "message", ...details}}
+fn error_schema() -> Schema {
+    let inner = ObjectBuilder::new()
+        .property("code", string()).required("code")
+        .property("message", string()).required("message")
+        .additional_properties(Some(ObjectBuilder::new()))
+        .description(Some("Extra keys depend on the code, e.g. bucket/requested_j/remaining_j for insufficient_balance"));
+    ObjectBuilder::new().property("error", inner).required("error").into()
+}
+
+fn routes() -> Vec<Op> {
+    use HttpMethod::{Get, Post, Put};
+    vec![
+        Op::new(Get, "/v1/sample", "Latest sample").api_version().units()
+            .query("cores", integer(), "1 adds per_core_usage and per_core_w").ok(Body::Named("SampleResponse")),
+        Op::new(Get, "/v1/bucket", "One bucket's balance").query("bucket", string(), "Bucket name (default \"default\")").units()
+            .ok(Body::Named("BucketResp")),
+        Op::new(Get, "/v1/schema", "JSON Schema of the sample payload").ok(Body::Object("JSON Schema (draft 2020-12)")),
+        Op::new(Get, "/v1/config", "Effective configuration").ok(Body::Object("Startup settings and live tunables")),
+        Op::new(Put, "/v1/config", "Change live tunables").body("TunablesPatch").ok(Body::Object("Effective configuration after the change"))
+            .errors(&[400, 401, 415]),
+        Op::new(Get, "/v1/history", "Buffered samples, or per-minute/per-hour aggregates").api_version()
+            .query("since", number(), "Only samples (or periods) after this ts")
+            .query("resolution", string().enum_values(Some(["sample", "minute", "hour"])), "Default sample")
+            .ok(Body::List("SampleResponse")),
+        Op::new(Get, "/v1/export", "Samples or aggregates in a time range as streamed NDJSON").api_version()
+            .query("from", number(), "Only rows after this ts, on the same clock as /v1/history since").query("to", number(), "Rows up to this ts (default: the request time)")
+            .query("fields", string(), "Comma-separated top-level fields to keep (default all)")
+            .query("resolution", string().enum_values(Some(["sample", "minute", "hour"])), "Default sample")
+            .ok_as("application/x-ndjson", "One JSON object per line, oldest first").errors(&[400]),
+        Op::new(Get, "/v1/recent", "Last n samples").api_version().query("n", integer(), "Default 30").ok(Body::List("SampleResponse")),
+        Op::new(Get, "/v1/health", "Liveness; the same body with 503 when the sampler stalls")
+            .ok(Body::Object("status, uptime_s, nvml, last_sample_age_s")),
+        Op::new(Get, "/v1/powercap", "NVML power limits of the metered GPUs")
+            .ok(Body::Object("devices: list of DeviceCap")).errors(&[403, 501, 503]),
+        Op::new(Post, "/v1/powercap", "Set the GPU power cap (requires JOULE_API_TOKEN)").body("PowerCapReq")
+            .ok(Body::Object("ok, devices: list of DeviceCap")).errors(&[400, 401, 403, 415, 501, 503]),
+        Op::new(Get, "/v1/top", "Approximate per-process power").query("n", integer(), "Processes per side (default 10)")
+            .ok(Body::Object("ts, cpu_w, gpu_w, cpu, gpu, method")),
+        Op::new(Get, "/v1/diag", "Sampler loop timing").ok(Body::Named("Report")),
+        Op::new(Get, "/v1/verify", "Check the hash chain over the history buffer")
+            .ok(Body::Object("ok, checked, broken_at_ts")).errors(&[404]),
+        Op::new(Get, "/v1/pubkey", "Ed25519 key verifying sample signatures").ok(Body::Object("alg, public_key")).errors(&[404]),
+        Op::new(Get, "/v1/stream", "Server-sent events: one sample per event, `lagged` events on overflow").api_version()
+            .ok_as("text/event-stream", "Sample events"),
+        Op::new(Get, "/v1/ws", "WebSocket: samples plus take/refund ops").api_version().ok_as("application/json", "101 Switching Protocols").errors(&[401]),
+        Op::new(Get, "/metrics", "Prometheus metrics").ok_as("text/plain", "Prometheus text exposition format"),
+        Op::new(Post, "/v1/take", "Spend joules from a bucket").body("TakeReq").ok(Body::Named("TakeResp")).errors(&[400, 401, 402, 415, 429, 503]),
+        Op::new(Get, "/v1/can_take", "Whether a take would succeed, without spending")
+            .query("joules", number(), "Required").query("bucket", string(), "Default \"default\"")
+            .ok(Body::Named("CanTakeResp")).errors(&[400]),
+        Op::new(Get, "/v1/takes", "Recent takes").query("limit", integer(), "Default 100").ok(Body::List("Entry")),
+        Op::new(Get, "/v1/take_stats", "Distribution of granted take sizes").ok(Body::Named("Summary")),
+        Op::new(Post, "/v1/take_batch", "Spend from several buckets, all or nothing").body("TakeBatchReq")
+            .ok(Body::Named("TakeBatchResp")).errors(&[400, 401, 402, 415, 429, 503]),
+        Op::new(Post, "/v1/admit", "Run, defer or reject a job by its energy estimate").body("AdmitReq")
+            .ok(Body::Named("Admission")).errors(&[400, 401, 415]),
+        Op::new(Post, "/v1/refund", "Return joules to a bucket").body("RefundReq").ok(Body::Named("RefundResp")).errors(&[400, 401, 415]),
+        Op::new(Post, "/v1/reserve", "Hold joules until committed, released or expired").body("ReserveReq")
+            .ok(Body::Named("ReserveResp")).errors(&[400, 401, 402, 415, 429, 503]),
+        Op::new(Post, "/v1/commit/{id}", "Keep a reservation's joules spent").id().ok(Body::Named("SettleResp")).errors(&[401, 404]),
+        Op::new(Post, "/v1/release/{id}", "Return a reservation's joules").id().ok(Body::Named("SettleResp")).errors(&[401, 404]),
+        Op::new(Post, "/v1/calibrate", "Measure the idle baselines over a window").query("window_s", number(), "Default JOULE_CALIBRATE_WINDOW_S")
+            .ok(Body::Named("CalibrateResp")).errors(&[400, 401, 503]),
+        Op::new(Post, "/v1/calibrate/reset", "Restore the configured idle baselines").ok(Body::Named("CalibrateResp")).errors(&[401]),
+        Op::new(Post, "/v1/extremes/reset", "Clear the power extremes in the sample").ok(Body::Object("ok")).errors(&[401]),
+        Op::new(Post, "/v1/pause", "Stop integrating and learning").ok(Body::Object("ok, paused")).errors(&[401]),
+        Op::new(Post, "/v1/resume", "Resume integrating").ok(Body::Object("ok, paused")).errors(&[401]),
+    ]
+}
+
+pub fn spec() -> OpenApi {
+    let mut doc = Components::openapi();
+    doc.info = InfoBuilder::new().title("CryoFlux joule agent").version(env!("CARGO_PKG_VERSION")).build();
+    if let Some(c) = doc.components.as_mut() {
+        c.schemas.insert("Error".into(), error_schema().into());
+    }
+    for r in routes() {
+        doc.paths.add_path_operation(r.path, vec![r.method], r.op.summary(Some(r.summary)));
+    }
+    doc
+}
+
+// assets from a CDN: bundling swagger-ui would add megabytes for an opt-in page
+pub const DOCS_HTML: &str = r##"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>CryoFlux API</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+<div id="ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#ui" });</script>
+</body>
+</html>
+"##;
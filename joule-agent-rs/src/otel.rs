@@ -0,0 +1,42 @@
+// OpenTelemetry OTLP/gRPC metrics export (JOULE_OTEL_ENDPOINT, cargo feature "otel")
+use crate::{power_gauges, Last, State};
+use opentelemetry::{metrics::MeterProvider as _, KeyValue};
+use opentelemetry_otlp::{MetricExporter, WithExportConfig};
+use opentelemetry_sdk::{metrics::{PeriodicReader, SdkMeterProvider}, Resource};
+use std::time::Duration;
+
+pub struct OtelCfg {
+    pub endpoint: String,
+    pub service_name: String,
+    pub interval_s: f64,
+}
+
+// observable gauges read the sampler's `Last` at collection time instead of sampling on their
+// own, so exported values always match /v1/sample
+pub fn start(st: &State, cfg: &OtelCfg) -> Result<SdkMeterProvider, String> {
+    let exporter = MetricExporter::builder()
+        .with_tonic()
+        .with_endpoint(&cfg.endpoint)
+        .build()
+        .map_err(|e| format!("OTLP exporter: {}", e))?;
+    let reader = PeriodicReader::builder(exporter)
+        .with_interval(Duration::from_secs_f64(cfg.interval_s.max(1.0)))
+        .build();
+    let resource = Resource::builder()
+        .with_service_name(cfg.service_name.clone())
+        .with_attribute(KeyValue::new("host.name", st.host.to_string()))
+        .with_attribute(KeyValue::new("host.id", st.machine_id.to_string()))
+        .build();
+    let provider = SdkMeterProvider::builder().with_reader(reader).with_resource(resource).build();
+
+    let meter = provider.meter("cryoflux");
+    for (i, g) in power_gauges(&Last::default()).into_iter().enumerate() {
+        let last = st.last.clone();
+        meter
+            .f64_observable_gauge(g.name)
+            .with_description(g.help)
+            .with_callback(move |o| o.observe(power_gauges(&last.lock())[i].value, &[]))
+            .build();
+    }
+    Ok(provider)
+}
@@ -0,0 +1,47 @@
+// Windows CPU power (PDH): the TDP estimate scaled by `% Processor Performance`, the current
+// clock relative to nominal (above 100 under turbo, below when the CPU parks or throttles)
+use windows_sys::Win32::System::Performance::{
+    PdhAddEnglishCounterW, PdhCloseQuery, PdhCollectQueryData, PdhGetFormattedCounterValue, PdhOpenQueryW,
+    PDH_FMT_COUNTERVALUE, PDH_FMT_DOUBLE,
+};
+
+const COUNTER: &str = "\\Processor Information(_Total)\\% Processor Performance";
+
+pub struct Pdh { query: isize, counter: isize }
+
+impl Pdh {
+    // None when PDH or the counter is unavailable (e.g. counters disabled), so callers use the TDP estimate
+    pub fn open() -> Option<Pdh> {
+        let path: Vec<u16> = COUNTER.encode_utf16().chain(std::iter::once(0)).collect();
+        let mut query = 0isize;
+        // SAFETY: out-pointers are valid and `path` is NUL-terminated UTF-16; once the query is open
+        // it is owned by `pdh`, whose Drop closes it on the early return
+        unsafe {
+            if PdhOpenQueryW(std::ptr::null(), 0, &mut query) != 0 { return None; }
+            let mut pdh = Pdh { query, counter: 0 };
+            if PdhAddEnglishCounterW(query, path.as_ptr(), 0, &mut pdh.counter) != 0 { return None; }
+            // rate counters need a first collection to diff against
+            PdhCollectQueryData(query);
+            Some(pdh)
+        }
+    }
+
+    pub fn performance_pct(&mut self) -> Option<f64> {
+        // SAFETY: handles are owned by self and valid until Drop
+        unsafe {
+            if PdhCollectQueryData(self.query) != 0 { return None; }
+            let mut value: PDH_FMT_COUNTERVALUE = std::mem::zeroed();
+            if PdhGetFormattedCounterValue(self.counter, PDH_FMT_DOUBLE, std::ptr::null_mut(), &mut value) != 0 {
+                return None;
+            }
+            Some(value.Anonymous.doubleValue).filter(|v| v.is_finite() && *v >= 0.0)
+        }
+    }
+}
+
+impl Drop for Pdh {
+    fn drop(&mut self) {
+        // SAFETY: the query was opened in `open` and is closed exactly once
+        unsafe { PdhCloseQuery(self.query); }
+    }
+}
@@ -0,0 +1,71 @@
+// Bucket + learned idle baselines persisted across restarts (JOULE_STATE_FILE), plus held
+// reservations with JOULE_SHUTDOWN_RESERVATIONS=keep
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io::Write, path::Path};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Persisted {
+    #[serde(default)]
+    pub buckets: HashMap<String, f64>,
+    // single-bucket files written before named buckets; folded into "default" on load
+    #[serde(default, skip_serializing)]
+    pub bucket_j: Option<f64>,
+    pub idle_gpu_w: f64,
+    pub idle_cpu_w: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_wall_w: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_dram_w: Option<f64>,
+    #[serde(default)]
+    pub total_integrated_j: f64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reservations: Vec<Held>,
+}
+
+// a reservation with its expiry as Unix time, so time spent stopped counts against the TTL
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Held {
+    pub id: u64,
+    pub bucket: String,
+    pub joules: f64,
+    pub expires_unix: f64,
+}
+
+// None (with a warning) when the file is missing or malformed, so startup uses the defaults
+pub fn load(path: &Path) -> Option<Persisted> {
+    let raw = match std::fs::read_to_string(path) {
+        Ok(raw) => raw,
+        Err(e) => {
+            tracing::warn!(path = %path.display(), error = %e, "state file not loaded, using defaults");
+            return None;
+        }
+    };
+    match serde_json::from_str::<Persisted>(&raw) {
+        Ok(p) if p.buckets.values().chain(&p.bucket_j).all(|v| v.is_finite())
+            && p.idle_gpu_w.is_finite() && p.idle_cpu_w.is_finite()
+            && p.idle_wall_w.is_none_or(f64::is_finite) && p.idle_dram_w.is_none_or(f64::is_finite)
+            && p.total_integrated_j.is_finite()
+            && p.reservations.iter().all(|r| r.joules.is_finite() && r.expires_unix.is_finite()) => Some(p),
+        Ok(_) => {
+            tracing::warn!(path = %path.display(), "state file has non-finite values, using defaults");
+            None
+        }
+        Err(e) => {
+            tracing::warn!(path = %path.display(), error = %e, "state file is malformed, using defaults");
+            None
+        }
+    }
+}
+
+// write to a sibling temp file, fsync, then rename over the target so a crash can't leave a torn file
+pub fn save(path: &Path, p: &Persisted) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = std::path::PathBuf::from(tmp);
+    {
+        let mut f = std::fs::File::create(&tmp)?;
+        f.write_all(serde_json::to_string(p)?.as_bytes())?;
+        f.sync_all()?;
+    }
+    std::fs::rename(&tmp, path)
+}
@@ -0,0 +1,63 @@
+// GPU power capping (JOULE_GPU_POWER_CAP_W, GET/POST /v1/powercap): NVML power management limits
+// on the metered devices. The cap is per device; setting it needs root
+use nvml_wrapper::{error::NvmlError, Nvml};
+use serde::Serialize;
+
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct DeviceCap {
+    index: u32,
+    enforced_w: Option<f64>, // what the driver applies; can sit below the set limit
+    min_w: f64,
+    max_w: f64,
+}
+
+pub enum CapErr {
+    OutOfRange(String),
+    Permission,
+    Unsupported(u32),
+    Nvml(String),
+}
+
+impl std::fmt::Display for CapErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CapErr::OutOfRange(msg) | CapErr::Nvml(msg) => f.write_str(msg),
+            CapErr::Permission => f.write_str("NVML refused to change the power limit; the agent must run as root"),
+            CapErr::Unsupported(i) => write!(f, "GPU {} does not support power limits", i),
+        }
+    }
+}
+
+fn nvml_err(index: u32, e: NvmlError) -> CapErr {
+    match e {
+        NvmlError::NoPermission => CapErr::Permission,
+        NvmlError::NotSupported => CapErr::Unsupported(index),
+        e => CapErr::Nvml(format!("GPU {}: {}", index, e)),
+    }
+}
+
+fn mw_to_w(mw: u32) -> f64 { mw as f64 / 1000.0 }
+
+pub fn limits(nvml: &Nvml, devices: &[u32]) -> Result<Vec<DeviceCap>, CapErr> {
+    devices.iter().map(|&index| {
+        let dev = nvml.device_by_index(index).map_err(|e| nvml_err(index, e))?;
+        let c = dev.power_management_limit_constraints().map_err(|e| nvml_err(index, e))?;
+        Ok(DeviceCap { index, enforced_w: dev.enforced_power_limit().ok().map(mw_to_w), min_w: mw_to_w(c.min_limit), max_w: mw_to_w(c.max_limit) })
+    }).collect()
+}
+
+// every device's bounds are checked before any limit changes, so a bad value changes nothing
+pub fn set(nvml: &Nvml, devices: &[u32], watts: f64) -> Result<Vec<DeviceCap>, CapErr> {
+    if !(watts.is_finite() && watts > 0.0) { return Err(CapErr::OutOfRange("watts must be > 0".into())); }
+    for cap in limits(nvml, devices)? {
+        if watts < cap.min_w || watts > cap.max_w {
+            return Err(CapErr::OutOfRange(format!("GPU {} accepts a cap of {}..{} W", cap.index, cap.min_w, cap.max_w)));
+        }
+    }
+    let mw = (watts * 1000.0).round() as u32;
+    for &index in devices {
+        let mut dev = nvml.device_by_index(index).map_err(|e| nvml_err(index, e))?;
+        dev.set_power_management_limit(mw).map_err(|e| nvml_err(index, e))?;
+    }
+    limits(nvml, devices)
+}
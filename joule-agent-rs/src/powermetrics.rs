@@ -0,0 +1,62 @@
+// Apple Silicon CPU/GPU package power from `powermetrics` (macOS, needs root)
+use parking_lot::Mutex;
+use std::{process::Stdio, sync::Arc};
+use tokio::{io::AsyncBufReadExt, process::Command, sync::watch};
+
+#[derive(Clone, Copy, Default)]
+pub struct Reading { pub cpu_w: f64, pub gpu_w: f64 }
+
+// "CPU Power: 1234 mW" -> 1.234
+fn parse_mw(line: &str, label: &str) -> Option<f64> {
+    let mw: f64 = line.strip_prefix(label)?.trim().strip_suffix("mW")?.trim().parse().ok()?;
+    Some(mw / 1000.0)
+}
+
+// one long-running child streaming a report every interval; the latest reading is held in the slot
+// (None while powermetrics isn't running, so the sampler uses the TDP estimate)
+pub fn spawn(interval_ms: u64, shutdown: watch::Receiver<bool>) -> Arc<Mutex<Option<Reading>>> {
+    let slot = Arc::new(Mutex::new(None));
+    let out = slot.clone();
+    tokio::spawn(async move {
+        // -n: never prompt; without passwordless sudo this fails fast instead of hanging
+        let child = Command::new("sudo")
+            .args(["-n", "powermetrics", "--samplers", "cpu_power,gpu_power", "-i", &interval_ms.to_string()])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match child {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::warn!(error = %e, "powermetrics not runnable, using the TDP estimate");
+                return;
+            }
+        };
+        let Some(stdout) = child.stdout.take() else { return };
+        let mut lines = tokio::io::BufReader::new(stdout).lines();
+        let mut current = Reading::default();
+        loop {
+            tokio::select! {
+                line = lines.next_line() => match line {
+                    Ok(Some(line)) => {
+                        if let Some(w) = parse_mw(&line, "CPU Power:") { current.cpu_w = w; }
+                        // GPU Power closes a cpu_power,gpu_power report
+                        if let Some(w) = parse_mw(&line, "GPU Power:") {
+                            current.gpu_w = w;
+                            *out.lock() = Some(current);
+                        }
+                    }
+                    Ok(None) | Err(_) => break,
+                },
+                _ = crate::shutdown_requested(shutdown.clone()) => return,
+            }
+        }
+        *out.lock() = None;
+        let status = child.wait().await.map(|s| s.to_string()).unwrap_or_default();
+        tracing::warn!(
+            %status,
+            "powermetrics exited; it needs root: run the agent as root or allow `sudo -n powermetrics` in sudoers. Using the TDP estimate"
+        );
+    });
+    slot
+}
@@ -0,0 +1,43 @@
+// Per-client token-bucket limit on the spending endpoints (JOULE_TAKE_RPS)
+use parking_lot::Mutex;
+use std::{collections::HashMap, net::IpAddr, time::{Duration, Instant}};
+
+// clients are told apart by the valid API token when they present it (hashed, so the secret isn't
+// kept around), otherwise by peer address
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Client { Token([u8; 32]), Ip(IpAddr) }
+
+struct Slot { tokens: f64, last: Instant }
+
+pub struct Limiter {
+    rps: f64,
+    burst: f64,
+    clients: Mutex<HashMap<Client, Slot>>,
+}
+
+impl Limiter {
+    pub fn new(rps: f64) -> Self {
+        Limiter { rps, burst: rps.max(1.0), clients: Mutex::new(HashMap::new()) }
+    }
+
+    // Err carries how long until the next request would be allowed
+    pub fn check(&self, client: Client) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock();
+        let slot = clients.entry(client).or_insert(Slot { tokens: self.burst, last: now });
+        slot.tokens = (slot.tokens + (now - slot.last).as_secs_f64() * self.rps).min(self.burst);
+        slot.last = now;
+        if slot.tokens >= 1.0 {
+            slot.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - slot.tokens) / self.rps))
+        }
+    }
+
+    // a client idle long enough to have refilled is indistinguishable from a new one
+    pub fn sweep(&self) {
+        let full_after = Duration::from_secs_f64(self.burst / self.rps);
+        self.clients.lock().retain(|_, s| s.last.elapsed() < full_after);
+    }
+}
@@ -0,0 +1,87 @@
+// SIGHUP re-reads the config file: the live tunables and the CPU curve take effect from the next
+// tick; anything else that changed is only reported, it needs a restart
+use crate::{config, load_cpu_curve, load_tunables, shutdown_requested, State};
+use tokio::signal::unix::{signal, SignalKind};
+
+// settings load_tunables / load_cpu_curve read; every other key is fixed at startup
+const LIVE_KEYS: &[&str] = &[
+    "JOULE_CPU_TDP_W",
+    "JOULE_SMOOTHING",
+    "JOULE_POWER_SMOOTHING",
+    "JOULE_INTEGRATE_HZ",
+    "JOULE_HZ",
+    "JOULE_PUBLISH_HZ",
+    "JOULE_IDLE_LEARN_W",
+    "JOULE_IDLE_LEARN_FRAC",
+    "JOULE_IDLE_LEARN_MODE",
+    "JOULE_CPU_CURVE",
+];
+
+pub async fn run(st: State, path: String) {
+    let mut hup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::warn!(error = %e, "cannot listen for SIGHUP, config reload disabled");
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            got = hup.recv() => if got.is_none() { break },
+            _ = shutdown_requested(st.shutdown.clone()) => break,
+        }
+        reload(&st, &path);
+    }
+}
+
+fn reload(st: &State, path: &str) {
+    let prev = match config::reload(path) {
+        Ok(prev) => prev,
+        Err(e) => {
+            tracing::warn!(error = %e, "config reload failed, keeping current settings");
+            return;
+        }
+    };
+    // validate everything before touching the live state; on error the old file stays current
+    let loaded = load_tunables().and_then(|(tune, sockets)| Ok((tune, sockets, load_cpu_curve()?)));
+    let (tune, socket_tdp_w, curve) = match loaded {
+        Ok(v) => v,
+        Err(e) => {
+            config::restore(prev);
+            tracing::warn!(error = %e, "invalid config on reload, keeping current settings");
+            return;
+        }
+    };
+
+    let old = serde_json::to_value(*st.tune.lock()).unwrap_or_default();
+    let new = serde_json::to_value(tune).unwrap_or_default();
+    let mut applied = 0;
+    if let (Some(old), Some(new)) = (old.as_object(), new.as_object()) {
+        for (k, to) in new {
+            let from = old.get(k).unwrap_or(&serde_json::Value::Null);
+            if from != to {
+                tracing::info!(setting = %k, %from, %to, "config reload: applied");
+                applied += 1;
+            }
+        }
+    }
+    *st.tune.lock() = tune;
+    {
+        let mut live = st.cpu_curve.lock();
+        if *live != curve {
+            tracing::info!(from = ?*live, to = ?curve, "config reload: applied cpu_curve");
+            *live = curve;
+            applied += 1;
+        }
+    }
+    // the estimate is split by the startup socket count; the new total applies regardless
+    if socket_tdp_w.len() != st.cfg.socket_tdp_w.len() {
+        tracing::warn!(setting = "JOULE_CPU_TDP_W", from = st.cfg.socket_tdp_w.len(), to = socket_tdp_w.len(), "config reload: socket count change requires restart");
+    }
+
+    let restart: Vec<String> = config::changed_keys(&prev).into_iter().filter(|k| !LIVE_KEYS.contains(&k.as_str())).collect();
+    for k in &restart {
+        tracing::warn!(setting = %k, "config reload: change requires restart");
+    }
+    tracing::info!(%path, applied, requires_restart = restart.len(), "config reloaded");
+}
@@ -0,0 +1,119 @@
+// Prometheus remote-write push (JOULE_PROM_REMOTE_WRITE_URL) for hosts that can't be scraped:
+// the /metrics series as a snappy-compressed WriteRequest protobuf every interval_s
+use crate::{metrics, scrape, shutdown_requested, State};
+use std::time::Duration;
+
+pub enum Auth { None, Basic(String, Option<String>), Bearer(String) }
+
+pub struct RemoteWriteCfg {
+    pub url: String,
+    pub auth: Auth,
+    pub interval_s: f64,
+    pub max_retries: u32,
+}
+
+pub async fn run(st: State, cfg: RemoteWriteCfg) {
+    let client = reqwest::Client::new();
+    let mut tick = tokio::time::interval(Duration::from_secs_f64(cfg.interval_s.max(1.0)));
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = tick.tick() => push(&client, &cfg, &write_request(&st)).await,
+            _ = shutdown_requested(st.shutdown.clone()) => break,
+        }
+    }
+    // the final values, so the last interval before a restart isn't lost
+    push(&client, &cfg, &write_request(&st)).await;
+}
+
+// one sample per series, all stamped with the collection time
+fn write_request(st: &State) -> Vec<u8> {
+    let (all, hist) = scrape(st);
+    let ts_ms = chrono::Utc::now().timestamp_millis();
+    let identity = [("host", &*st.host), ("machine_id", &*st.machine_id)];
+    let mut series: Vec<(String, Option<String>, f64)> = all.iter().map(|m| (m.name.to_string(), None, m.value)).collect();
+    series.extend(hist.buckets.iter().map(|&(bound, n)| (format!("{}_bucket", hist.name), Some(metrics::le(bound)), n as f64)));
+    series.push((format!("{}_sum", hist.name), None, hist.sum));
+    series.push((format!("{}_count", hist.name), None, hist.count as f64));
+
+    let mut req = Vec::new();
+    for (name, le, value) in &series {
+        // receivers require labels sorted by name
+        let mut labels: Vec<(&str, &str)> = vec![("__name__", name)];
+        labels.extend(identity);
+        if let Some(le) = le { labels.push(("le", le)); }
+        labels.sort_by(|a, b| a.0.cmp(b.0));
+        let mut ts = Vec::new();
+        for (k, v) in labels {
+            let mut label = Vec::new();
+            bytes_field(&mut label, 1, k.as_bytes());
+            bytes_field(&mut label, 2, v.as_bytes());
+            bytes_field(&mut ts, 1, &label);
+        }
+        let mut sample = Vec::new();
+        sample.push(1 << 3 | 1); // value: double
+        sample.extend_from_slice(&value.to_le_bytes());
+        sample.push(2 << 3); // timestamp: int64 varint
+        varint(&mut sample, ts_ms as u64);
+        bytes_field(&mut ts, 2, &sample);
+        bytes_field(&mut req, 1, &ts);
+    }
+    req
+}
+
+// the few protobuf encodings WriteRequest needs: varints and length-delimited fields
+fn varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn bytes_field(out: &mut Vec<u8>, field: u8, data: &[u8]) {
+    out.push(field << 3 | 2);
+    varint(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+// retry transient failures (network, 429, 5xx) with exponential backoff; drop the request after
+// max_retries, the next interval sends fresh values anyway
+async fn push(client: &reqwest::Client, cfg: &RemoteWriteCfg, req: &[u8]) {
+    let body = match snap::raw::Encoder::new().compress_vec(req) {
+        Ok(b) => b,
+        Err(e) => {
+            tracing::warn!(error = %e, "cannot snappy-encode the remote-write request");
+            return;
+        }
+    };
+    let mut backoff = Duration::from_millis(500);
+    for attempt in 0..=cfg.max_retries {
+        let mut rb = client
+            .post(&cfg.url)
+            .header("Content-Type", "application/x-protobuf")
+            .header("Content-Encoding", "snappy")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body.clone());
+        rb = match &cfg.auth {
+            Auth::None => rb,
+            Auth::Basic(user, password) => rb.basic_auth(user, password.as_deref()),
+            Auth::Bearer(token) => rb.bearer_auth(token),
+        };
+        let transient = match rb.send().await {
+            Ok(r) if r.status().is_success() => return,
+            Ok(r) => {
+                let status = r.status();
+                tracing::warn!(%status, attempt, "remote write rejected");
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, attempt, "remote write failed");
+                true
+            }
+        };
+        if !transient || attempt == cfg.max_retries { break; }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(30));
+    }
+    tracing::warn!("dropping remote-write request");
+}
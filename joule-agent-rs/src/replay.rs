@@ -0,0 +1,69 @@
+// Recorded power trace fed to the sampler instead of the hardware (JOULE_REPLAY_FILE)
+use std::path::Path;
+
+#[derive(Clone, Copy, serde::Deserialize)]
+pub struct Row { pub ts: f64, pub gpu_w: f64, pub cpu_w: f64 }
+
+pub struct Step {
+    pub gpu_w: f64,
+    pub cpu_w: f64,
+    pub dt: Option<f64>, // from the trace's timestamps; None = use the tick interval
+}
+
+pub struct Replay {
+    rows: Vec<Row>,
+    next: usize,
+    looping: bool,
+    done: bool,
+}
+
+// CSV `ts,gpu_w,cpu_w` (header line optional) or JSON lines `{"ts":…,"gpu_w":…,"cpu_w":…}`
+pub fn load(path: &Path) -> Result<Vec<Row>, String> {
+    let raw = std::fs::read_to_string(path).map_err(|e| format!("cannot read JOULE_REPLAY_FILE {}: {}", path.display(), e))?;
+    let mut rows = Vec::new();
+    for (n, line) in raw.lines().enumerate().map(|(i, l)| (i + 1, l.trim())).filter(|(_, l)| !l.is_empty()) {
+        let row = if line.starts_with('{') {
+            serde_json::from_str::<Row>(line).map_err(|e| e.to_string())
+        } else {
+            let cols: Vec<Result<f64, _>> = line.split(',').map(|c| c.trim().parse::<f64>()).collect();
+            match cols.as_slice() {
+                [Ok(ts), Ok(gpu_w), Ok(cpu_w)] => Ok(Row { ts: *ts, gpu_w: *gpu_w, cpu_w: *cpu_w }),
+                _ if n == 1 && rows.is_empty() => continue, // header
+                _ => Err("expected ts,gpu_w,cpu_w".to_string()),
+            }
+        };
+        match row {
+            Ok(r) if r.ts.is_finite() && r.gpu_w.is_finite() && r.cpu_w.is_finite() => rows.push(r),
+            Ok(_) => return Err(format!("{}:{}: non-finite value", path.display(), n)),
+            Err(e) => return Err(format!("{}:{}: {}", path.display(), n, e)),
+        }
+    }
+    if rows.is_empty() { return Err(format!("{} has no rows", path.display())); }
+    Ok(rows)
+}
+
+impl Replay {
+    pub fn new(rows: Vec<Row>, looping: bool) -> Replay { Replay { rows, next: 0, looping, done: false } }
+
+    // one row per sampler tick. At the end of a non-looping trace the last row is held and
+    // integrates nothing (dt 0), so the bucket stops where the recording did
+    pub fn step(&mut self) -> Step {
+        if self.next == self.rows.len() {
+            if self.looping {
+                self.next = 0;
+            } else {
+                if !self.done {
+                    self.done = true;
+                    tracing::info!(rows = self.rows.len(), "replay finished, holding the last reading");
+                }
+                let last = self.rows[self.rows.len() - 1];
+                return Step { gpu_w: last.gpu_w, cpu_w: last.cpu_w, dt: Some(0.0) };
+            }
+        }
+        let row = self.rows[self.next];
+        // the first row (and the first after a loop) has no predecessor to measure from
+        let dt = self.next.checked_sub(1).map(|i| row.ts - self.rows[i].ts).filter(|dt| *dt > 0.0);
+        self.next += 1;
+        Step { gpu_w: row.gpu_w, cpu_w: row.cpu_w, dt }
+    }
+}
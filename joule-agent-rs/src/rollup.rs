@@ -0,0 +1,83 @@
+// Long-term history (GET /v1/history?resolution=minute|hour): per-minute and per-hour aggregates
+// built by the sampler as it goes, kept for JOULE_HISTORY_MINUTES / JOULE_HISTORY_HOURS periods
+use serde::Serialize;
+use std::collections::VecDeque;
+
+#[derive(Clone, Serialize, utoipa::ToSchema)]
+pub struct Period {
+    pub ts: f64, // Unix start of the period, aligned to the minute/hour
+    pub net_w_avg: f64,
+    pub net_w_peak: f64,
+    pub joules: f64, // integrated into the bucket during the period
+    pub ticks: u64,
+    pub partial: bool, // the period still in progress
+}
+
+struct Tier { span_s: f64, keep: usize, done: VecDeque<Period>, open: Option<(Period, f64)> } // (period, net_w sum)
+
+impl Tier {
+    fn new(span_s: f64, keep: usize) -> Tier {
+        Tier { span_s, keep, done: VecDeque::with_capacity(keep.min(4096)), open: None }
+    }
+
+    fn push(&mut self, ts: f64, net_w: f64, joules: f64) {
+        let start = (ts / self.span_s).floor() * self.span_s;
+        if self.open.as_ref().is_some_and(|(p, _)| p.ts != start) {
+            if let Some((p, _)) = self.open.take() {
+                if self.done.len() >= self.keep { self.done.pop_front(); }
+                if self.keep > 0 { self.done.push_back(Period { partial: false, ..p }); }
+            }
+        }
+        let (p, sum) = self.open.get_or_insert((Period { ts: start, net_w_avg: 0.0, net_w_peak: net_w, joules: 0.0, ticks: 0, partial: true }, 0.0));
+        *sum += net_w;
+        p.ticks += 1;
+        p.net_w_avg = *sum / p.ticks as f64;
+        p.net_w_peak = p.net_w_peak.max(net_w);
+        p.joules += joules;
+    }
+
+    // oldest first, the open period last
+    fn since(&self, since: f64) -> Vec<Period> {
+        self.done.iter().chain(self.open.as_ref().map(|(p, _)| p)).filter(|p| p.ts > since).cloned().collect()
+    }
+
+    // at most `limit` periods with after < ts <= to, oldest first, so a caller can walk the tier
+    // in chunks without copying all of it
+    fn range(&self, after: f64, to: f64, limit: usize) -> Vec<Period> {
+        self.done.iter().chain(self.open.as_ref().map(|(p, _)| p)).filter(|p| p.ts > after && p.ts <= to).take(limit).cloned().collect()
+    }
+}
+
+pub struct Rollups { minute: Tier, hour: Tier }
+
+#[derive(Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution { #[default] Sample, Minute, Hour }
+
+impl Rollups {
+    pub fn new(minutes: usize, hours: usize) -> Rollups {
+        Rollups { minute: Tier::new(60.0, minutes), hour: Tier::new(3600.0, hours) }
+    }
+
+    // every sampler tick, integrated or not (joules 0 then)
+    pub fn push(&mut self, ts: f64, net_w: f64, joules: f64) {
+        self.minute.push(ts, net_w, joules);
+        self.hour.push(ts, net_w, joules);
+    }
+
+    pub fn since(&self, resolution: Resolution, since: f64) -> Vec<Period> {
+        match resolution {
+            Resolution::Minute => self.minute.since(since),
+            Resolution::Hour => self.hour.since(since),
+            Resolution::Sample => Vec::new(),
+        }
+    }
+
+    pub fn range(&self, resolution: Resolution, after: f64, to: f64, limit: usize) -> Vec<Period> {
+        match resolution {
+            Resolution::Minute => self.minute.range(after, to, limit),
+            Resolution::Hour => self.hour.range(after, to, limit),
+            Resolution::Sample => Vec::new(),
+        }
+    }
+}
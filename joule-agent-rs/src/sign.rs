@@ -0,0 +1,30 @@
+// Ed25519 sample attestation (JOULE_SIGN_KEY)
+use ed25519_dalek::{pkcs8::DecodePrivateKey, Signer, SigningKey};
+
+// JOULE_SIGN_KEY holds the key itself or a path to it: a PKCS#8 PEM block or a 32-byte hex seed
+pub fn load_key(raw: &str) -> Result<SigningKey, String> {
+    let text = match std::fs::read_to_string(raw) {
+        Ok(contents) => contents,
+        Err(_) => raw.to_string(),
+    };
+    let text = text.trim();
+    if text.starts_with("-----BEGIN") {
+        return SigningKey::from_pkcs8_pem(text).map_err(|e| format!("invalid PEM key: {}", e));
+    }
+    let seed: [u8; 32] = hex::decode(text)
+        .map_err(|e| format!("invalid hex key: {}", e))?
+        .try_into()
+        .map_err(|_| "hex key must be 32 bytes".to_string())?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+// signs the compact serde_json serialization; serde_json::Map keeps keys sorted, so verifiers
+// reproduce the input by dropping `sig` and re-serializing the object
+pub fn sign_json(key: &SigningKey, v: &serde_json::Value) -> String {
+    let canonical = serde_json::to_string(v).unwrap_or_default();
+    hex::encode(key.sign(canonical.as_bytes()).to_bytes())
+}
+
+pub fn public_key_hex(key: &SigningKey) -> String {
+    hex::encode(key.verifying_key().to_bytes())
+}
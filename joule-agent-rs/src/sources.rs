@@ -0,0 +1,468 @@
+// Power sources polled by the sampler: each backend implements PowerSource and is registered
+// once at startup; the Registry is what the sampler iterates every tick
+use crate::{interpolate, read_u64, Battery, GpuPowerMethod, PowerMode};
+use nvml_wrapper::enum_wrappers::device::Sampling;
+use nvml_wrapper::enums::device::SampleValue;
+use nvml_wrapper::{Device, Nvml};
+use parking_lot::Mutex;
+use std::{path::PathBuf, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, Instant}};
+
+// what the sampler measured itself this tick, shared with every source
+#[derive(Clone, Copy)]
+pub struct Tick {
+    pub dt: f64,
+    pub cpu_usage: f32, // 0..100, averaged over cores
+    // false until a second CPU refresh: usage is garbage before that
+    pub cpu_ready: bool,
+    pub cpu_tdp_w: f64,
+    pub battery: Option<Battery>,
+    // GPU total of this tick; filled in before the CPU sources are polled
+    pub gpu_w: f64,
+}
+
+pub struct PowerReading {
+    pub watts: f64,
+    // GPU sources: one entry per device; CPU sources: one per socket
+    pub per_device_w: Vec<f64>,
+    // reported as cpu_source; usually the source name, the estimate says how it estimated
+    pub label: &'static str,
+    // estimated from warm-up CPU usage: published flagged, kept out of the EMAs and the bucket
+    pub warmup: bool,
+}
+
+impl PowerReading {
+    fn new(watts: f64, label: &'static str) -> Self {
+        PowerReading { watts, per_device_w: vec![watts], label, warmup: false }
+    }
+}
+
+pub trait PowerSource: Send {
+    fn name(&self) -> &'static str;
+    // false for sources that model power rather than read a sensor
+    fn measured(&self) -> bool { true }
+    // None when the source has nothing this tick (not ready, read failed, not applicable)
+    fn sample(&mut self, tick: &Tick) -> Option<PowerReading>;
+}
+
+// GPU readings add up across sources; CPU sources are in priority order and the first reading wins.
+// Every source is polled every tick, used or not, so delta-based ones (RAPL) stay one tick wide
+#[derive(Default)]
+pub struct Registry {
+    gpu: Vec<Box<dyn PowerSource>>,
+    cpu: Vec<Box<dyn PowerSource>>,
+}
+
+// how trustworthy a sample's power is: every reading from a sensor, some of it modelled, or nothing read
+#[derive(Clone, Copy, Default, PartialEq, serde::Serialize, schemars::JsonSchema, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality { Measured, Estimated, #[default] Unavailable }
+
+impl Quality {
+    // cryoflux_power_quality gauge value
+    pub fn level(self) -> f64 {
+        match self { Quality::Measured => 2.0, Quality::Estimated => 1.0, Quality::Unavailable => 0.0 }
+    }
+}
+
+pub struct Sampled {
+    pub gpu_w: f64,
+    pub per_gpu_w: Vec<f64>,
+    pub cpu_w: f64,
+    pub per_socket_w: Vec<f64>,
+    pub cpu_source: &'static str,
+    pub warmup: bool,
+    // sources that contributed to this sample: every GPU source with a reading, then the CPU winner
+    pub active: Vec<&'static str>,
+    pub quality: Quality,
+    // how long each source took to read, in polling order (GET /v1/diag)
+    pub latency: Vec<(&'static str, Duration)>,
+}
+
+impl Registry {
+    pub fn add_gpu(&mut self, s: Box<dyn PowerSource>) { self.gpu.push(s); }
+    pub fn add_cpu(&mut self, s: Box<dyn PowerSource>) { self.cpu.push(s); }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.gpu.iter().chain(&self.cpu).map(|s| s.name()).collect()
+    }
+
+    pub fn sample(&mut self, tick: &Tick) -> Sampled {
+        let mut active = Vec::new();
+        let mut latency = Vec::new();
+        let mut per_gpu_w = Vec::new();
+        for s in self.gpu.iter_mut() {
+            let started = Instant::now();
+            let r = s.sample(tick);
+            latency.push((s.name(), started.elapsed()));
+            if let Some(r) = r {
+                per_gpu_w.extend(r.per_device_w);
+                active.push(s.name());
+            }
+        }
+        let gpu_w = per_gpu_w.iter().fold(0.0, |a, w| a + w);
+        let tick = Tick { gpu_w, ..*tick };
+        let mut winner: Option<(&'static str, bool, PowerReading)> = None;
+        for s in self.cpu.iter_mut() {
+            let started = Instant::now();
+            let r = s.sample(&tick);
+            latency.push((s.name(), started.elapsed()));
+            if winner.is_none() { winner = r.map(|r| (s.name(), s.measured(), r)); }
+        }
+        // GPU readings all come from sensors, so the CPU side decides
+        let (cpu_w, per_socket_w, cpu_source, warmup, quality) = match winner {
+            Some((name, measured, r)) => {
+                active.push(name);
+                (r.watts, r.per_device_w, r.label, r.warmup, if measured { Quality::Measured } else { Quality::Estimated })
+            }
+            None if !active.is_empty() => (0.0, vec![0.0], "none", false, Quality::Estimated),
+            None => (0.0, vec![0.0], "none", false, Quality::Unavailable),
+        };
+        Sampled { gpu_w, per_gpu_w, cpu_w, per_socket_w, cpu_source, warmup, active, quality, latency }
+    }
+}
+
+// NVIDIA GPUs; shares the handle with the telemetry readers and re-initializes it when reads keep failing
+pub struct NvmlSource {
+    slot: Arc<Mutex<Option<Nvml>>>,
+    watch: NvmlWatch,
+    healthy: Arc<AtomicBool>,
+    devices: Vec<u32>,
+    method: GpuPowerMethod,
+    prev_mj: Vec<Option<u64>>, // last energy counter reading, per entry of `devices`
+    last_us: Vec<Option<u64>>, // newest power sample timestamp consumed, per entry of `devices`
+}
+
+impl NvmlSource {
+    // the flag mirrors NvmlWatch's verdict for /v1/health; `devices` are the indices to meter
+    pub fn new(slot: Arc<Mutex<Option<Nvml>>>, healthy: Arc<AtomicBool>, devices: Vec<u32>, method: GpuPowerMethod) -> Self {
+        if let Some(n) = slot.lock().as_ref() {
+            for &i in &devices {
+                match method {
+                    GpuPowerMethod::EnergyCounter if n.device_by_index(i).and_then(|d| d.total_energy_consumption()).is_err() => {
+                        tracing::warn!(index = i, "GPU has no energy counter, using instantaneous power");
+                    }
+                    GpuPowerMethod::Buffer if n.device_by_index(i).and_then(|d| d.samples(Sampling::Power, None)).is_err() => {
+                        tracing::warn!(index = i, "GPU has no power sample buffer, using instantaneous power");
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let prev_mj = vec![None; devices.len()];
+        let last_us = vec![None; devices.len()];
+        NvmlSource { slot, watch: NvmlWatch::new(), healthy, devices, method, prev_mj, last_us }
+    }
+}
+
+// watts from the millijoule counter delta over dt; None on the first reading, a failed read, or a
+// counter that went backwards (a driver reload restarts it), and power_usage stands in that tick
+fn counter_w(prev: &mut Option<u64>, now: Option<u64>, dt: f64) -> Option<f64> {
+    match (std::mem::replace(prev, now), now) {
+        (Some(p), Some(c)) if c >= p && dt > 0.0 => Some((c - p) as f64 / 1000.0 / dt),
+        _ => None,
+    }
+}
+
+// mean of the power samples the driver buffered after `last` (a few per second, so sub-tick
+// spikes reach the integral); None on the first reading, which only sets the mark, when nothing
+// new was buffered, or where the buffer isn't supported, and power_usage stands in that tick
+fn buffer_w(dev: &Device, last: &mut Option<u64>) -> Option<f64> {
+    let Ok(samples) = dev.samples(Sampling::Power, last.unwrap_or(0)) else { return None };
+    let fresh: Vec<f64> = samples.iter()
+        .filter(|s| last.is_none_or(|l| s.timestamp > l))
+        .map(|s| match s.value {
+            SampleValue::U32(mw) => mw as f64,
+            SampleValue::U64(mw) => mw as f64,
+            SampleValue::F64(mw) => mw,
+            SampleValue::I64(mw) => mw as f64,
+        })
+        .collect();
+    let first = last.is_none();
+    if let Some(newest) = samples.iter().map(|s| s.timestamp).max() { *last = Some(newest.max(last.unwrap_or(0))); }
+    if first || fresh.is_empty() { return None; }
+    Some(fresh.iter().sum::<f64>() / fresh.len() as f64 / 1000.0)
+}
+
+impl PowerSource for NvmlSource {
+    fn name(&self) -> &'static str { "nvml" }
+
+    // sum over the selected devices, skipping ones that fail to read
+    fn sample(&mut self, tick: &Tick) -> Option<PowerReading> {
+        let (per, read_ok) = {
+            let n = self.slot.lock();
+            let mut per = Vec::new();
+            let mut ok = false;
+            match n.as_ref() {
+                Some(n) if n.device_count().is_ok() => {
+                    for ((&i, prev), last) in self.devices.iter().zip(self.prev_mj.iter_mut()).zip(self.last_us.iter_mut()) {
+                        let Ok(dev) = n.device_by_index(i) else { *prev = None; *last = None; continue };
+                        let counted = match self.method {
+                            GpuPowerMethod::EnergyCounter => counter_w(prev, dev.total_energy_consumption().ok(), tick.dt),
+                            GpuPowerMethod::Buffer => buffer_w(&dev, last),
+                            GpuPowerMethod::Instant => None,
+                        };
+                        if let Some(w) = counted.or_else(|| dev.power_usage().ok().map(|mw| mw as f64 / 1000.0)) { per.push(w); }
+                    }
+                    ok = self.devices.is_empty() || !per.is_empty();
+                }
+                // the handle is gone or stale: counters restart with the next one
+                _ => {
+                    self.prev_mj.iter_mut().for_each(|p| *p = None);
+                    self.last_us.iter_mut().for_each(|l| *l = None);
+                }
+            }
+            (per, ok)
+        };
+        self.healthy.store(self.watch.observe(&self.slot, read_ok), Ordering::Relaxed);
+        if per.is_empty() { return None; }
+        Some(PowerReading { watts: per.iter().sum(), per_device_w: per, label: "nvml", warmup: false })
+    }
+}
+
+// Re-initializes NVML after the driver goes away (module reload, nvidia-smi reset, suspend/resume),
+// which otherwise leaves every read failing for the life of the process
+struct NvmlWatch { failures: u32, backoff: Duration, retry_at: Instant }
+
+impl NvmlWatch {
+    const FAILURE_THRESHOLD: u32 = 3;
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    fn new() -> Self {
+        NvmlWatch { failures: 0, backoff: Duration::from_secs(1), retry_at: Instant::now() }
+    }
+
+    // feed one tick's read result; returns whether NVML is healthy
+    fn observe(&mut self, slot: &Mutex<Option<Nvml>>, read_ok: bool) -> bool {
+        let now = Instant::now();
+        if read_ok {
+            if self.failures >= Self::FAILURE_THRESHOLD { tracing::info!("NVML readings restored"); }
+            self.failures = 0;
+            self.backoff = Duration::from_secs(1);
+            return true;
+        }
+        self.failures += 1;
+        if self.failures == Self::FAILURE_THRESHOLD {
+            tracing::warn!("NVML reads failing, re-initializing");
+            *slot.lock() = None;
+            self.retry_at = now;
+        }
+        if self.failures >= Self::FAILURE_THRESHOLD && now >= self.retry_at {
+            match Nvml::init() {
+                Ok(n) => {
+                    tracing::info!("NVML re-initialized");
+                    *slot.lock() = Some(n);
+                }
+                Err(e) => tracing::debug!(error = %e, retry_in_s = self.backoff.as_secs(), "NVML re-init failed"),
+            }
+            self.retry_at = now + self.backoff;
+            self.backoff = (self.backoff * 2).min(Self::MAX_BACKOFF);
+        }
+        false
+    }
+}
+
+// amdgpu (Linux): hwmon power sensor of every AMD card, in microwatts
+pub struct Amdgpu { sensors: Vec<PathBuf> }
+
+impl Amdgpu {
+    // older kernels expose power1_average, newer ones power1_input
+    pub fn probe() -> Option<Amdgpu> {
+        let mut sensors = Vec::new();
+        for card in std::fs::read_dir("/sys/class/drm").ok()?.flatten() {
+            let name = card.file_name().to_string_lossy().into_owned();
+            // cardN only; cardN-DP-1 etc. are connectors
+            if !name.starts_with("card") || name.contains('-') { continue; }
+            let dev = card.path().join("device");
+            if std::fs::read_to_string(dev.join("vendor")).map_or(true, |v| v.trim() != "0x1002") { continue; }
+            let Ok(hwmons) = std::fs::read_dir(dev.join("hwmon")) else { continue };
+            for hwmon in hwmons.flatten() {
+                let found = ["power1_average", "power1_input"].iter().map(|f| hwmon.path().join(f)).find(|p| read_u64(p).is_some());
+                if let Some(p) = found { sensors.push(p); break; }
+            }
+        }
+        sensors.sort();
+        if sensors.is_empty() { None } else { Some(Amdgpu { sensors }) }
+    }
+
+    pub fn devices(&self) -> usize { self.sensors.len() }
+}
+
+impl PowerSource for Amdgpu {
+    fn name(&self) -> &'static str { "amdgpu" }
+
+    fn sample(&mut self, _: &Tick) -> Option<PowerReading> {
+        let per: Vec<f64> = self.sensors.iter().filter_map(|p| read_u64(p)).map(|uw| uw as f64 / 1e6).collect();
+        if per.is_empty() { return None; }
+        Some(PowerReading { watts: per.iter().sum(), per_device_w: per, label: "amdgpu", warmup: false })
+    }
+}
+
+// Apple Silicon package power from the powermetrics reader; one source per side
+#[cfg(target_os = "macos")]
+pub struct Powermetrics { slot: Arc<Mutex<Option<crate::powermetrics::Reading>>>, gpu: bool }
+
+#[cfg(target_os = "macos")]
+impl Powermetrics {
+    pub fn gpu(slot: Arc<Mutex<Option<crate::powermetrics::Reading>>>) -> Self { Powermetrics { slot, gpu: true } }
+    pub fn cpu(slot: Arc<Mutex<Option<crate::powermetrics::Reading>>>) -> Self { Powermetrics { slot, gpu: false } }
+}
+
+#[cfg(target_os = "macos")]
+impl PowerSource for Powermetrics {
+    fn name(&self) -> &'static str { "powermetrics" }
+
+    fn sample(&mut self, _: &Tick) -> Option<PowerReading> {
+        let r = (*self.slot.lock())?;
+        Some(PowerReading::new(if self.gpu { r.gpu_w } else { r.cpu_w }, "powermetrics"))
+    }
+}
+
+// battery discharge is whole-system power: attribute what the GPU doesn't account for.
+// Only consulted with JOULE_POWER_SOURCE=battery and while off AC
+pub struct BatterySource { mode: PowerMode }
+
+impl BatterySource {
+    pub fn new(mode: PowerMode) -> Self { BatterySource { mode } }
+}
+
+impl PowerSource for BatterySource {
+    fn name(&self) -> &'static str { "battery" }
+
+    fn sample(&mut self, tick: &Tick) -> Option<PowerReading> {
+        let b = tick.battery.filter(|b| self.mode == PowerMode::Battery && !b.on_ac)?;
+        Some(PowerReading::new((b.watts - tick.gpu_w).max(0.0), "battery"))
+    }
+}
+
+// RAPL (CPU package energy via Linux powercap sysfs)
+struct RaplDomain { energy_path: PathBuf, max_uj: u64, prev_uj: Option<u64> }
+pub struct Rapl { domains: Vec<RaplDomain> }
+
+impl Rapl {
+    // top-level package domains only (intel-rapl:N); subdomains (intel-rapl:N:M) are already
+    // included in their package counter. AMD exposes the same interface on recent kernels.
+    pub fn probe() -> Option<Rapl> {
+        let mut domains = Vec::new();
+        for entry in std::fs::read_dir("/sys/class/powercap").ok()?.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with("intel-rapl:") || name.matches(':').count() != 1 { continue; }
+            let dir = entry.path();
+            let energy_path = dir.join("energy_uj");
+            // energy_uj is root-only on newer kernels: an unreadable counter means no RAPL
+            if read_u64(&energy_path).is_none() { continue; }
+            let max_uj = read_u64(&dir.join("max_energy_range_uj")).unwrap_or(0);
+            domains.push(RaplDomain { energy_path, max_uj, prev_uj: None });
+        }
+        // intel-rapl:0, intel-rapl:1, ... in socket order
+        domains.sort_by(|a, b| a.energy_path.cmp(&b.energy_path));
+        if domains.is_empty() { None } else { Some(Rapl { domains }) }
+    }
+}
+
+impl PowerSource for Rapl {
+    fn name(&self) -> &'static str { "rapl" }
+
+    // package power, one entry per package (socket)
+    fn sample(&mut self, tick: &Tick) -> Option<PowerReading> {
+        let per = domain_watts(&mut self.domains, tick.dt)?;
+        Some(PowerReading { watts: per.iter().sum(), per_device_w: per, label: "rapl", warmup: false })
+    }
+}
+
+// energy_uj deltas over dt seconds, per domain; None until two readings exist or on read error
+fn domain_watts(domains: &mut [RaplDomain], dt: f64) -> Option<Vec<f64>> {
+    let mut per_uj = Vec::with_capacity(domains.len());
+    let mut complete = true;
+    for d in domains.iter_mut() {
+        let now = read_u64(&d.energy_path)?;
+        match d.prev_uj {
+            Some(prev) if now >= prev => per_uj.push(now - prev),
+            Some(prev) => {
+                // counter wrapped: use the advertised range, else assume a 32- or 64-bit counter
+                let range = if d.max_uj > 0 { d.max_uj }
+                    else if prev <= u32::MAX as u64 { u32::MAX as u64 } else { u64::MAX };
+                per_uj.push((range - prev).saturating_add(now));
+            }
+            None => complete = false,
+        }
+        d.prev_uj = Some(now);
+    }
+    if !complete || dt <= 0.0 { return None; }
+    Some(per_uj.iter().map(|&uj| uj as f64 / 1e6 / dt).collect())
+}
+
+// RAPL DRAM domains (JOULE_DRAM): memory power, a term of its own next to CPU and GPU rather
+// than a power source, since neither side covers it
+pub struct Dram { domains: Vec<RaplDomain> }
+
+impl Dram {
+    // any intel-rapl domain named "dram", usually the subdomain intel-rapl:N:0 of each package
+    pub fn probe() -> Option<Dram> {
+        let mut domains = Vec::new();
+        for entry in std::fs::read_dir("/sys/class/powercap").ok()?.flatten() {
+            if !entry.file_name().to_string_lossy().starts_with("intel-rapl:") { continue; }
+            let dir = entry.path();
+            if std::fs::read_to_string(dir.join("name")).map_or(true, |n| n.trim() != "dram") { continue; }
+            let energy_path = dir.join("energy_uj");
+            if read_u64(&energy_path).is_none() { continue; }
+            let max_uj = read_u64(&dir.join("max_energy_range_uj")).unwrap_or(0);
+            domains.push(RaplDomain { energy_path, max_uj, prev_uj: None });
+        }
+        domains.sort_by(|a, b| a.energy_path.cmp(&b.energy_path));
+        if domains.is_empty() { None } else { Some(Dram { domains }) }
+    }
+
+    pub fn domains(&self) -> usize { self.domains.len() }
+
+    // summed over the domains; None on the first call or a failed read
+    pub fn watts(&mut self, dt: f64) -> Option<f64> {
+        domain_watts(&mut self.domains, dt).map(|per| per.iter().sum())
+    }
+}
+
+// JOULE_CPU_CURVE util:watts breakpoints, shared with State so SIGHUP can swap them
+pub type SharedCurve = Arc<Mutex<Option<Vec<(f64, f64)>>>>;
+
+// CPU power estimated from utilization: the JOULE_CPU_CURVE, else linear up to the TDP; on
+// Windows PDH scales it by the current clock. Always has a reading, so it goes last. Usage isn't
+// known per socket, so the estimate is split by each socket's share of the configured TDP
+pub struct Estimate {
+    curve: SharedCurve,
+    socket_shares: Vec<f64>,
+    #[cfg(windows)]
+    pdh: Option<crate::pdh::Pdh>,
+}
+
+impl Estimate {
+    pub fn new(curve: SharedCurve, socket_tdp_w: &[f64]) -> Self {
+        let total: f64 = socket_tdp_w.iter().sum();
+        Estimate {
+            curve,
+            socket_shares: socket_tdp_w.iter().map(|w| w / total).collect(),
+            #[cfg(windows)]
+            pdh: crate::pdh::Pdh::open(),
+        }
+    }
+}
+
+impl PowerSource for Estimate {
+    fn name(&self) -> &'static str { "estimate" }
+    fn measured(&self) -> bool { false }
+
+    fn sample(&mut self, tick: &Tick) -> Option<PowerReading> {
+        #[cfg(windows)]
+        let pdh_perf = self.pdh.as_mut().and_then(|p| p.performance_pct());
+        #[cfg(not(windows))]
+        let pdh_perf: Option<f64> = None;
+        let (est_w, label) = match &*self.curve.lock() {
+            Some(curve) => (interpolate(curve, tick.cpu_usage as f64), "curve"),
+            None => ((tick.cpu_usage as f64 / 100.0) * tick.cpu_tdp_w, "tdp"),
+        };
+        let (watts, label) = match pdh_perf {
+            Some(perf_pct) => (est_w * perf_pct / 100.0, "pdh"),
+            None => (est_w, label),
+        };
+        let per_device_w = self.socket_shares.iter().map(|share| watts * share).collect();
+        Some(PowerReading { watts, per_device_w, label, warmup: !tick.cpu_ready })
+    }
+}
@@ -0,0 +1,79 @@
+// Rolling average and peak of net power over fixed windows (JOULE_STATS_WINDOWS)
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+
+struct Window {
+    label: String, // "1m", "5m": the key suffix in the `stats` object
+    span_s: f64,
+    samples: VecDeque<(f64, f64)>, // (ts, watts), oldest first
+    sum: f64,
+    evicted: usize,
+    // monotonic deque: decreasing watts, so the front is the peak of the window
+    peak: VecDeque<(f64, f64)>,
+}
+
+pub struct Rolling { windows: Vec<Window> }
+
+// "60s", "1m", "5m", "1h" (a bare number is seconds); comma-separated, label kept verbatim
+pub fn parse_windows(raw: &str) -> Result<Vec<(String, f64)>, String> {
+    raw.split(',').map(str::trim).filter(|w| !w.is_empty()).map(|w| {
+        let (num, unit) = match w.strip_suffix('h') {
+            Some(n) => (n, 3600.0),
+            None => match w.strip_suffix('m') {
+                Some(n) => (n, 60.0),
+                None => (w.strip_suffix('s').unwrap_or(w), 1.0),
+            },
+        };
+        match num.parse::<f64>() {
+            Ok(n) if n.is_finite() && n > 0.0 => Ok((w.to_string(), n * unit)),
+            _ => Err(format!("invalid JOULE_STATS_WINDOWS entry {:?} (e.g. 1m,5m)", w)),
+        }
+    }).collect()
+}
+
+impl Rolling {
+    pub fn new(windows: &[(String, f64)]) -> Rolling {
+        Rolling {
+            windows: windows.iter().map(|(label, span_s)| Window {
+                label: label.clone(), span_s: *span_s, samples: VecDeque::new(), sum: 0.0, evicted: 0, peak: VecDeque::new(),
+            }).collect(),
+        }
+    }
+
+    // amortized O(1) per window
+    pub fn push(&mut self, ts: f64, w: f64) {
+        for win in &mut self.windows {
+            win.samples.push_back((ts, w));
+            win.sum += w;
+            while win.peak.back().is_some_and(|&(_, p)| p <= w) { win.peak.pop_back(); }
+            win.peak.push_back((ts, w));
+
+            let cutoff = ts - win.span_s;
+            while win.samples.front().is_some_and(|&(t, _)| t <= cutoff) {
+                let (_, old) = win.samples.pop_front().unwrap_or_default();
+                win.sum -= old;
+                win.evicted += 1;
+            }
+            while win.peak.front().is_some_and(|&(t, _)| t <= cutoff) { win.peak.pop_front(); }
+            // re-add from scratch once per window's worth of evictions so float drift can't accumulate
+            if win.evicted >= win.samples.len().max(1) {
+                win.sum = win.samples.iter().map(|&(_, w)| w).sum();
+                win.evicted = 0;
+            }
+        }
+    }
+
+    // net_w_avg_<label>, net_w_peak_<label>, net_w_peak_ts_<label>; null until the first sample
+    pub fn snapshot(&self) -> Map<String, Value> {
+        let mut out = Map::new();
+        for win in &self.windows {
+            let n = win.samples.len();
+            let avg = (n > 0).then(|| win.sum / n as f64);
+            let peak = win.peak.front();
+            out.insert(format!("net_w_avg_{}", win.label), serde_json::json!(avg));
+            out.insert(format!("net_w_peak_{}", win.label), serde_json::json!(peak.map(|p| p.1)));
+            out.insert(format!("net_w_peak_ts_{}", win.label), serde_json::json!(peak.map(|p| p.0)));
+        }
+        out
+    }
+}
@@ -0,0 +1,94 @@
+// Distribution of granted take sizes (GET /v1/take_stats, cryoflux_take_joules histogram)
+use serde::Serialize;
+
+pub struct TakeHist {
+    bounds: Vec<f64>, // ascending upper bounds; an implicit +Inf bucket follows
+    counts: Vec<u64>, // per bucket (not cumulative), bounds.len() + 1 entries
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct Bucket { pub le: Option<f64>, pub count: u64 } // le None = +Inf; count is cumulative
+
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct Summary {
+    pub count: u64,
+    pub sum_j: f64,
+    pub min_j: Option<f64>,
+    pub max_j: Option<f64>,
+    // estimated by linear interpolation within the bucket, like Prometheus' histogram_quantile
+    pub p50_j: Option<f64>,
+    pub p95_j: Option<f64>,
+    pub buckets: Vec<Bucket>,
+}
+
+pub fn parse_bounds(raw: &str) -> Result<Vec<f64>, String> {
+    let mut bounds = Vec::new();
+    for item in raw.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+        match item.parse::<f64>() {
+            Ok(b) if b.is_finite() && b > 0.0 && bounds.last().is_none_or(|&prev| b > prev) => bounds.push(b),
+            _ => return Err(format!("invalid JOULE_TAKE_HIST_BUCKETS entry {:?} (ascending positive numbers, e.g. 1,10,100,1000)", item)),
+        }
+    }
+    Ok(bounds)
+}
+
+impl TakeHist {
+    pub fn new(bounds: Vec<f64>) -> TakeHist {
+        let counts = vec![0; bounds.len() + 1];
+        TakeHist { bounds, counts, sum: 0.0, min: f64::INFINITY, max: f64::NEG_INFINITY }
+    }
+
+    pub fn observe(&mut self, joules: f64) {
+        let i = self.bounds.partition_point(|&b| b < joules);
+        self.counts[i] += 1;
+        self.sum += joules;
+        self.min = self.min.min(joules);
+        self.max = self.max.max(joules);
+    }
+
+    pub fn count(&self) -> u64 { self.counts.iter().sum() }
+    pub fn sum(&self) -> f64 { self.sum }
+
+    pub fn cumulative(&self) -> Vec<Bucket> {
+        let mut acc = 0;
+        self.counts.iter().enumerate().map(|(i, c)| {
+            acc += c;
+            Bucket { le: self.bounds.get(i).copied(), count: acc }
+        }).collect()
+    }
+
+    // the +Inf bucket and the first bucket interpolate towards the observed max/min instead of
+    // an unbounded edge, and estimates are clamped to the observed range
+    fn quantile(&self, q: f64) -> Option<f64> {
+        let total = self.count();
+        if total == 0 { return None; }
+        let rank = q * total as f64;
+        let mut below = 0;
+        for (i, &c) in self.counts.iter().enumerate() {
+            if c > 0 && (below + c) as f64 >= rank {
+                let lo = if i == 0 { self.min } else { self.bounds[i - 1] };
+                let hi = self.bounds.get(i).copied().unwrap_or(self.max);
+                let est = lo + (hi - lo) * (rank - below as f64) / c as f64;
+                return Some(est.clamp(self.min, self.max));
+            }
+            below += c;
+        }
+        Some(self.max)
+    }
+
+    pub fn summary(&self) -> Summary {
+        let count = self.count();
+        Summary {
+            count,
+            sum_j: self.sum,
+            min_j: (count > 0).then_some(self.min),
+            max_j: (count > 0).then_some(self.max),
+            p50_j: self.quantile(0.5),
+            p95_j: self.quantile(0.95),
+            buckets: self.cumulative(),
+        }
+    }
+}
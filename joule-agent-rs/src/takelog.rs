@@ -0,0 +1,31 @@
+// Recent takes with the power state they happened in (GET /v1/takes); bounded like the sample history
+use serde::Serialize;
+use std::collections::VecDeque;
+
+#[derive(Clone, Serialize, utoipa::ToSchema)]
+pub struct Entry {
+    pub ts: f64, // Unix time
+    pub bucket: String,
+    pub requested_j: f64,
+    pub granted_j: f64, // requested_j, less for a partial take, 0 when denied
+    pub ok: bool,
+    pub remaining_j: f64,
+    pub net_w: f64, // compute_net_w of the latest sample at the time of the take
+}
+
+pub struct TakeLog { entries: VecDeque<Entry>, cap: usize }
+
+impl TakeLog {
+    pub fn new(cap: usize) -> TakeLog { TakeLog { entries: VecDeque::with_capacity(cap), cap } }
+
+    pub fn record(&mut self, e: Entry) {
+        if self.cap == 0 { return; }
+        if self.entries.len() >= self.cap { self.entries.pop_front(); }
+        self.entries.push_back(e);
+    }
+
+    // newest first
+    pub fn recent(&self, limit: usize) -> Vec<Entry> {
+        self.entries.iter().rev().take(limit).cloned().collect()
+    }
+}
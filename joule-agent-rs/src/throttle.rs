@@ -0,0 +1,44 @@
+// Throttling hints for /v1/sample: NVML clock-throttle reasons per GPU, and the Linux
+// thermal_throttle counters on Intel CPUs. Low power while throttled isn't low demand
+use nvml_wrapper::{bitmasks::device::ThrottleReasons, Device};
+use std::path::PathBuf;
+
+// reasons that mean the clocks were pulled down against the workload; idle, application and
+// display clock settings are left out
+const GPU_REASONS: &[(ThrottleReasons, &str)] = &[
+    (ThrottleReasons::SW_POWER_CAP, "sw_power_cap"),
+    (ThrottleReasons::HW_SLOWDOWN, "hw_slowdown"),
+    (ThrottleReasons::SW_THERMAL_SLOWDOWN, "sw_thermal_slowdown"),
+    (ThrottleReasons::HW_THERMAL_SLOWDOWN, "hw_thermal_slowdown"),
+    (ThrottleReasons::HW_POWER_BRAKE_SLOWDOWN, "hw_power_brake_slowdown"),
+];
+
+// empty when the GPU doesn't report throttle reasons
+pub fn gpu_reasons(dev: &Device) -> Vec<&'static str> {
+    let Ok(active) = dev.current_throttle_reasons() else { return Vec::new() };
+    GPU_REASONS.iter().filter(|(r, _)| active.contains(*r)).map(|(_, name)| *name).collect()
+}
+
+// sum of every CPU's package and core throttle counters; they only ever count up
+pub struct CpuThrottle { counters: Vec<PathBuf>, prev: Option<u64> }
+
+impl CpuThrottle {
+    // None where the kernel exposes no thermal_throttle counters (non-Intel, VMs)
+    pub fn probe() -> Option<CpuThrottle> {
+        let mut counters = Vec::new();
+        for cpu in std::fs::read_dir("/sys/devices/system/cpu").ok()?.flatten() {
+            let dir = cpu.path().join("thermal_throttle");
+            for f in ["package_throttle_count", "core_throttle_count"] {
+                let p = dir.join(f);
+                if crate::read_u64(&p).is_some() { counters.push(p); }
+            }
+        }
+        if counters.is_empty() { None } else { Some(CpuThrottle { counters, prev: None }) }
+    }
+
+    // whether any counter moved since the previous call
+    pub fn poll(&mut self) -> bool {
+        let total: u64 = self.counters.iter().filter_map(|p| crate::read_u64(p)).sum();
+        self.prev.replace(total).is_some_and(|prev| total > prev)
+    }
+}
@@ -0,0 +1,67 @@
+// Approximate per-process power attribution (GET /v1/top)
+use nvml_wrapper::{enums::device::UsedGpuMemory, Nvml};
+use parking_lot::Mutex;
+use serde::Serialize;
+use sysinfo::{Pid, System};
+
+pub const METHOD: &str = "cpu_w is split by each process's share of total process CPU time over a \
+    short measurement window; each GPU's power is split by its compute processes' share of used GPU \
+    memory (evenly when the driver does not report memory). Estimates only.";
+
+#[derive(Serialize)]
+pub struct CpuProc { pid: u32, name: String, cpu_pct: f32, watts: f64 }
+
+#[derive(Serialize)]
+pub struct GpuProc { pid: u32, name: String, gpu: u32, gpu_mem_mb: Option<f64>, watts: f64 }
+
+// blocking: measures process CPU usage over MINIMUM_CPU_UPDATE_INTERVAL, so call off the async workers
+// `per_gpu_w` starts with the metered NVML devices, in `gpu_devices` order
+pub fn top(n: usize, cpu_w: f64, per_gpu_w: &[f64], gpu_devices: &[u32], nvml: &Mutex<Option<Nvml>>) -> (Vec<CpuProc>, Vec<GpuProc>) {
+    let mut sys = System::new();
+    sys.refresh_processes();
+    std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+    sys.refresh_processes();
+
+    let total: f64 = sys.processes().values().map(|p| p.cpu_usage() as f64).sum();
+    let mut cpu: Vec<CpuProc> = sys
+        .processes()
+        .iter()
+        .map(|(pid, p)| CpuProc {
+            pid: pid.as_u32(),
+            name: p.name().to_string(),
+            cpu_pct: p.cpu_usage(),
+            watts: if total > 0.0 { cpu_w * p.cpu_usage() as f64 / total } else { 0.0 },
+        })
+        .collect();
+    cpu.sort_by(|a, b| b.cpu_pct.total_cmp(&a.cpu_pct));
+    cpu.truncate(n);
+
+    let mut gpu = Vec::new();
+    if let Some(nvml) = nvml.lock().as_ref() {
+        for (&i, &dev_w) in gpu_devices.iter().zip(per_gpu_w) {
+            let Ok(procs) = nvml.device_by_index(i).and_then(|d| d.running_compute_processes()) else { continue };
+            let mem: Vec<Option<u64>> = procs
+                .iter()
+                .map(|p| match p.used_gpu_memory { UsedGpuMemory::Used(b) => Some(b), UsedGpuMemory::Unavailable => None })
+                .collect();
+            let mem_total: u64 = mem.iter().flatten().sum();
+            for (p, m) in procs.iter().zip(&mem) {
+                let share = if mem_total > 0 {
+                    m.unwrap_or(0) as f64 / mem_total as f64
+                } else {
+                    1.0 / procs.len() as f64
+                };
+                gpu.push(GpuProc {
+                    pid: p.pid,
+                    name: sys.process(Pid::from_u32(p.pid)).map(|p| p.name().to_string()).unwrap_or_default(),
+                    gpu: i,
+                    gpu_mem_mb: m.map(|b| b as f64 / (1024.0 * 1024.0)),
+                    watts: dev_w * share,
+                });
+            }
+        }
+    }
+    gpu.sort_by(|a, b| b.watts.total_cmp(&a.watts));
+    gpu.truncate(n);
+    (cpu, gpu)
+}
@@ -0,0 +1,62 @@
+// Smart-plug wall power (JOULE_WALLPLUG_URL): Tasmota, Shelly or anything serving JSON watts
+use crate::{shutdown_requested, State};
+use std::time::Duration;
+
+pub struct WallplugCfg {
+    pub url: String,
+    pub field: Option<String>, // JSON pointer to the watts value; None tries the known plug formats
+    pub interval_s: f64,
+}
+
+// Tasmota `cm?cmnd=Status 8` / tele SENSOR, Shelly Gen1 `/status` and `/meter/0`, Shelly Gen2 `Switch.GetStatus`
+const KNOWN_FIELDS: &[&str] = &["/StatusSNS/ENERGY/Power", "/ENERGY/Power", "/meters/0/power", "/apower", "/power"];
+
+// a plug that stops answering for this many polls is treated as gone, so samples fall back to the model
+const MAX_FAILURES: u32 = 3;
+
+pub async fn run(st: State, cfg: WallplugCfg) {
+    let client = reqwest::Client::new();
+    let mut tick = tokio::time::interval(Duration::from_secs_f64(cfg.interval_s.max(0.5)));
+    let mut failures = 0;
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {}
+            _ = shutdown_requested(st.shutdown.clone()) => break,
+        }
+        match fetch(&client, &cfg).await {
+            Ok(w) => {
+                if failures >= MAX_FAILURES { tracing::info!(wall_w = w, "wall power restored"); }
+                failures = 0;
+                *st.wall_w.lock() = Some(w);
+            }
+            Err(e) => {
+                failures += 1;
+                tracing::warn!(error = %e, failures, "wall plug poll failed");
+                if failures == MAX_FAILURES {
+                    tracing::warn!("wall power unavailable, using the model estimate");
+                    *st.wall_w.lock() = None;
+                }
+            }
+        }
+    }
+}
+
+async fn fetch(client: &reqwest::Client, cfg: &WallplugCfg) -> Result<f64, String> {
+    let body: serde_json::Value = client
+        .get(&cfg.url)
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    let found = match &cfg.field {
+        Some(f) => body.pointer(f).and_then(|v| v.as_f64()),
+        None => KNOWN_FIELDS.iter().find_map(|f| body.pointer(f).and_then(|v| v.as_f64())),
+    };
+    found
+        .filter(|w| w.is_finite() && *w >= 0.0)
+        .ok_or_else(|| "no non-negative power value in the plug response".to_string())
+}
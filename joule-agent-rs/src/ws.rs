@@ -0,0 +1,101 @@
+// WebSocket control channel (GET /v1/ws): take/refund over one persistent connection, plus an
+// opt-in live sample feed on the same socket
+use crate::{check_joules, check_warm, ApiError, ApiVersion, default_bucket, ratelimit, shutdown_requested, versioned_json, Last, State};
+use axum::extract::ws::{Message, WebSocket};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// {"op": "take", "joules": 5, "bucket"?: "gpu", "id"?: any}. `id` is echoed in the reply so a
+// pipelining client can match answers to requests
+#[derive(Deserialize)]
+struct Frame {
+    #[serde(default)]
+    id: Option<Value>,
+    #[serde(flatten)]
+    cmd: Command,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Command {
+    Take { joules: f64, #[serde(default = "default_bucket")] bucket: String, #[serde(default)] allow_partial: bool },
+    Refund { joules: f64, #[serde(default = "default_bucket")] bucket: String },
+    Subscribe,
+    Unsubscribe,
+}
+
+// `version` is the sample schema of the feed, resolved at upgrade like /v1/stream's
+pub async fn serve(mut socket: WebSocket, st: State, client: ratelimit::Client, version: ApiVersion) {
+    let mut samples: Option<broadcast::Receiver<Last>> = None;
+    let shutdown = shutdown_requested(st.shutdown.clone());
+    tokio::pin!(shutdown);
+    loop {
+        let out = tokio::select! {
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Text(text))) => handle(&st, &client, &text, &mut samples),
+                Some(Ok(Message::Binary(_))) => json!({ "op": "error", "id": null, "error": "expected a JSON text frame" }),
+                // ping/pong are answered by axum
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            },
+            s = next_sample(&mut samples) => match s {
+                Ok(s) => json!({ "op": "sample", "sample": versioned_json(&s, version) }),
+                // a slow reader skips samples and is told how many, like /v1/stream
+                Err(RecvError::Lagged(n)) => json!({ "op": "lagged", "missed": n }),
+                Err(RecvError::Closed) => break,
+            },
+            _ = &mut shutdown => {
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            }
+        };
+        if socket.send(Message::Text(out.to_string())).await.is_err() { break; }
+    }
+}
+
+async fn next_sample(rx: &mut Option<broadcast::Receiver<Last>>) -> Result<Last, RecvError> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// malformed or rejected commands get an error object; the socket stays open
+fn handle(st: &State, client: &ratelimit::Client, text: &str, samples: &mut Option<broadcast::Receiver<Last>>) -> Value {
+    let frame: Frame = match serde_json::from_str(text) {
+        Ok(f) => f,
+        Err(e) => return json!({ "op": "error", "id": null, "error": format!("invalid command: {}", e) }),
+    };
+    let id = frame.id;
+    match frame.cmd {
+        Command::Take { joules, bucket, allow_partial } => {
+            if let Err(e) = check_warm(st).and(check_joules(joules)) { return api_error(id, e); }
+            // same per-client budget as POST /v1/take
+            if let Some(wait) = st.take_limiter.as_ref().and_then(|l| l.check(client.clone()).err()) {
+                return json!({ "op": "error", "id": id, "error": "rate limit exceeded", "retry_after_s": wait.as_secs_f64() });
+            }
+            let r = st.take(&bucket, joules, allow_partial);
+            let mut reply = json!({ "op": "take", "id": id, "ok": r.ok, "remaining_j": r.remaining_j });
+            if let Some(g) = r.granted_j { reply["granted_j"] = json!(g); }
+            reply
+        }
+        Command::Refund { joules, bucket } => {
+            if let Err(e) = check_joules(joules) { return api_error(id, e); }
+            let r = st.refund(&bucket, joules);
+            json!({ "op": "refund", "id": id, "ok": r.ok, "remaining_j": r.remaining_j })
+        }
+        Command::Subscribe => {
+            if samples.is_none() { *samples = Some(st.samples_tx.subscribe()); }
+            json!({ "op": "subscribe", "id": id, "ok": true })
+        }
+        Command::Unsubscribe => {
+            *samples = None;
+            json!({ "op": "unsubscribe", "id": id, "ok": true })
+        }
+    }
+}
+
+fn api_error(id: Option<Value>, e: ApiError) -> Value {
+    json!({ "op": "error", "id": id, "error": e.message, "code": e.code })
+}